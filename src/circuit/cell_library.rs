@@ -2,14 +2,24 @@ use std::{collections::HashMap, str::FromStr};
 
 use itertools::Itertools;
 
+use crate::netlist::json_netlist::PortDirection;
+
 use super::node::NodeType;
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct CellLibrary {
     cell_name_map: HashMap<String, String>,
     cell_map: HashMap<String, NodeType>,
     input_port_order: HashMap<String, Vec<String>>,
     output_port_order: HashMap<String, Vec<String>>,
+    /// sequential cells which can only be blackboxes, with the reason why they can not be masked
+    unsupported: HashMap<String, String>,
+}
+
+impl Default for CellLibrary {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl CellLibrary {
@@ -20,15 +30,43 @@ impl CellLibrary {
             ("DFF", vec!["C", "D"]),
         ];
 
+        let outputs = [
+            ("BUF", vec!["Y"]),
+            ("NOT", vec!["Y"]),
+            ("AND", vec!["Y"]),
+            ("NAND", vec!["Y"]),
+            ("OR", vec!["Y"]),
+            ("NOR", vec!["Y"]),
+            ("XOR", vec!["Y"]),
+            ("XNOR", vec!["Y"]),
+            ("MUX", vec!["Z"]),
+            ("DFF", vec!["Q"]),
+        ];
+
         let m = m.map(|(k, v)| (k.to_string(), v.iter().map(|s| s.to_string()).collect_vec()));
+        let outputs =
+            outputs.map(|(k, v)| (k.to_string(), v.iter().map(|s| s.to_string()).collect_vec()));
         CellLibrary {
             cell_name_map: HashMap::new(),
             cell_map: HashMap::new(),
             input_port_order: HashMap::from(m),
-            output_port_order: HashMap::new(),
+            output_port_order: HashMap::from(outputs),
+            unsupported: HashMap::new(),
         }
     }
 
+    /// Library cell implementing `node_type`, the first by name if several cells do.
+    /// `None` for node types without a cell of the library, e.g. of the default library.
+    pub fn cell_type_of(&self, node_type: &NodeType) -> Option<&str> {
+        self.cell_map
+            .iter()
+            .filter(|(cell_type, nt)| {
+                *nt == node_type && !self.unsupported.contains_key(*cell_type)
+            })
+            .map(|(cell_type, _)| cell_type.as_str())
+            .min()
+    }
+
     pub fn get_input_port_order(&self, cell: &str) -> Option<&Vec<String>> {
         self.input_port_order.get(cell)
     }
@@ -37,6 +75,71 @@ impl CellLibrary {
         self.output_port_order.get(cell)
     }
 
+    /// Add a cell, replacing any cell of the same name.
+    /// `inputs` are in the order of the input ports of `node_type`.
+    pub(crate) fn add_cell(
+        &mut self,
+        cell_type: &str,
+        node_type: NodeType,
+        inputs: Vec<String>,
+        outputs: Vec<String>,
+    ) {
+        match node_type.cell_type_name() {
+            Some(name) if !matches!(node_type, NodeType::Blackbox(_)) => {
+                self.cell_name_map.insert(cell_type.to_owned(), name);
+            }
+            _ => {
+                self.cell_name_map.remove(cell_type);
+            }
+        }
+        self.unsupported.remove(cell_type);
+        self.cell_map.insert(cell_type.to_owned(), node_type);
+        self.input_port_order.insert(cell_type.to_owned(), inputs);
+        self.output_port_order.insert(cell_type.to_owned(), outputs);
+    }
+
+    /// Add a sequential cell which can not be mapped to a register, as a blackbox.
+    /// Netlists instantiating it are rejected with the `reason`, e.g. an asynchronous clear.
+    pub(crate) fn add_unsupported_cell(
+        &mut self,
+        cell_type: &str,
+        reason: String,
+        inputs: Vec<String>,
+        outputs: Vec<String>,
+    ) {
+        self.add_cell(
+            cell_type,
+            NodeType::Blackbox(cell_type.to_owned()),
+            inputs,
+            outputs,
+        );
+        self.unsupported.insert(cell_type.to_owned(), reason);
+    }
+
+    /// Why a cell can not be masked, for sequential cells other than plain D flip-flops
+    pub fn unsupported_reason(&self, cell_type: &str) -> Option<&str> {
+        self.unsupported.get(cell_type).map(String::as_str)
+    }
+
+    /// Direction of a port of a library cell. Ports of cells without a known output port
+    /// order are assumed to be inputs, unless they are named `Y`.
+    pub fn port_direction(&self, cell_type: &str, port: &str) -> Option<PortDirection> {
+        let outputs = self.get_output_port_order(cell_type).or_else(|| {
+            self.cell_name_map
+                .get(cell_type)
+                .and_then(|name| self.get_output_port_order(name))
+        });
+        match outputs {
+            Some(outputs) if outputs.iter().any(|p| p == port) => Some(PortDirection::Output),
+            Some(_) => Some(PortDirection::Input),
+            None => match self.node_type_from_cell_type(cell_type)? {
+                NodeType::Blackbox(_) => None,
+                _ if port == "Y" => Some(PortDirection::Output),
+                _ => Some(PortDirection::Input),
+            },
+        }
+    }
+
     pub fn node_type_from_cell_type(&self, cell_type: &str) -> Option<NodeType> {
         let direct_node = self.cell_map.get(cell_type);
         if direct_node.is_some() {
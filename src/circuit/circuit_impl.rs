@@ -1,23 +1,26 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::convert::identity;
 
 use crate::circuit::node::GateType;
 
 use super::node::{Node, NodePortId, NodeType};
-use super::{Circuit, NodeIndex};
+use super::{Circuit, GadgetKind, Label, NodeIndex};
 use boolinator::Boolinator;
 use itertools::Itertools;
-use petgraph::{
-    visit::{Dfs, EdgeRef},
-    Direction,
-};
+use petgraph::{visit::EdgeRef, Direction};
+
+/// name of a share of a node, e.g. `data_s1[3]` for share 1 of `data[3]`
+pub(crate) fn share_name(name: &str, share: u8) -> String {
+    let (l, r) = name.rfind('[').map_or((name, ""), |i| name.split_at(i));
+    format!("{}_s{}{}", l, share, r)
+}
 
 impl Circuit {
     pub fn add_node(&mut self, node: Node) -> NodeIndex {
         let node_type = node.node_type.clone();
         let id = self.graph.add_node(node);
         match node_type {
-            NodeType::Input => {
+            NodeType::Input | NodeType::Control | NodeType::Random => {
                 self.inputs.insert(id).expect("duplicate input");
             }
             NodeType::Clock => {
@@ -60,6 +63,16 @@ impl Circuit {
         }
     }
 
+    /// scheme of the gadgets, once masked
+    pub fn gadget(&self) -> GadgetKind {
+        self.gadget
+    }
+
+    /// Cycles by which the secure outputs lag behind the unmasked circuit
+    pub fn latency(&self) -> usize {
+        self.latency
+    }
+
     pub fn secure_inputs(&self) -> Vec<NodeIndex> {
         self.inputs
             .iter()
@@ -73,6 +86,54 @@ impl Circuit {
             .collect()
     }
 
+    pub fn control_inputs(&self) -> Vec<NodeIndex> {
+        self.inputs
+            .iter()
+            .copied()
+            .filter(|&id| self.graph[id].node_type == NodeType::Control)
+            .collect()
+    }
+
+    pub fn random_inputs(&self) -> Vec<NodeIndex> {
+        self.inputs
+            .iter()
+            .copied()
+            .filter(|&id| self.graph[id].node_type == NodeType::Random)
+            .collect()
+    }
+
+    /// Fresh random bits used by a gadget in each cycle
+    pub fn gadget_randomness(&self, node_type: &NodeType) -> usize {
+        let (NodeType::Gadget {
+            base_type,
+            num_shares,
+            ..
+        }, Some(num_operands)) = (node_type, node_type.num_gadget_operands())
+        else {
+            return 0;
+        };
+        // n-ary gates are chains of 2-input gadgets
+        let num_instances = match base_type {
+            GateType::Mux => 1,
+            _ => num_operands.saturating_sub(1),
+        };
+        num_instances * self.gadget.gadget().randomness(*num_shares as usize)
+    }
+
+    /// Cycles from the inputs to the outputs of a gadget
+    pub fn gadget_latency(&self, node_type: &NodeType) -> usize {
+        let (NodeType::Gadget { base_type, .. }, Some(num_operands)) =
+            (node_type, node_type.num_gadget_operands())
+        else {
+            return 0;
+        };
+        let num_stages = match base_type {
+            GateType::Mux => 1,
+            _ => num_operands.saturating_sub(1),
+        };
+        num_stages * self.gadget.gadget().latency()
+    }
+
     pub fn secure_outputs(&self) -> Vec<NodeIndex> {
         self.outputs
             .iter()
@@ -86,15 +147,141 @@ impl Circuit {
             .collect()
     }
 
+    /// Label the nodes carrying a secret, see [Circuit::propagate_labels], and mark those
+    /// carrying a secret to mask as secure
     pub fn propagate_secure(&mut self) {
-        for start in self.secure_inputs() {
-            let mut dfs = Dfs::new(&self.graph, start);
-            while let Some(nx) = dfs.next(&self.graph) {
-                self.graph[nx].secure = true;
-            }
+        self.labels = self.propagate_labels();
+        for (&nx, label) in self.labels.iter() {
+            self.graph[nx].secure = self.is_masked(label);
+        }
+    }
+
+    /// Make output `port` of `nx` public, by a declassification buffer driving its fanout
+    pub fn declassify(&mut self, nx: NodeIndex, port: NodePortId) -> NodeIndex {
+        let bx = self.insert_buffer(nx, port);
+        self.declassified.insert(bx);
+        bx
+    }
+
+    pub fn declassified(&self) -> &HashSet<NodeIndex> {
+        &self.declassified
+    }
+
+    /// Make output `port` of `nx` a secret with `label`, by a buffer driving its fanout which
+    /// is a source of the secure taint
+    pub fn classify(&mut self, nx: NodeIndex, port: NodePortId, label: Label) -> NodeIndex {
+        let bx = self.insert_buffer(nx, port);
+        self.classified.insert(bx, label);
+        bx
+    }
+
+    pub fn classified(&self) -> &HashMap<NodeIndex, Label> {
+        &self.classified
+    }
+
+    /// Declare the input `nx` as share `share` of a secret with `label`, which masking wires
+    /// to that share instead of sharing it again. Returns the classified buffer of `nx`.
+    pub fn preshare(&mut self, nx: NodeIndex, share: u8, label: Label) -> NodeIndex {
+        let bx = self.classify(nx, 0, label);
+        self.preshared.insert(bx, share);
+        bx
+    }
+
+    /// Share index and label of a pre-shared input, see [Circuit::preshare]
+    pub fn input_share(&self, nx: NodeIndex) -> Option<(u8, &Label)> {
+        self.graph
+            .neighbors_directed(nx, Direction::Outgoing)
+            .find_map(|bx| Some((*self.preshared.get(&bx)?, self.classified.get(&bx)?)))
+    }
+
+    /// buffer between output `port` of `nx` and its fanout
+    fn insert_buffer(&mut self, nx: NodeIndex, port: NodePortId) -> NodeIndex {
+        let fanout = self
+            .graph
+            .edges_directed(nx, Direction::Outgoing)
+            .filter(|e| e.weight().0 == port)
+            .map(|e| (e.id(), e.target(), e.weight().1))
+            .collect_vec();
+        let bx = self.add_node(Node {
+            secure: false,
+            node_type: NodeType::Gate(GateType::Buf, false),
+            name: None,
+        });
+        for (e, dx, dst_port) in fanout {
+            self.graph.remove_edge(e);
+            self.connect(bx, 0, dx, dst_port);
         }
+        self.connect(nx, port, bx, 0);
+        bx
     }
 
+    /// Recombine the shares at the declassification point `nx`, which becomes a public XOR.
+    /// Like for [Circuit::replicate_node], `nx` takes share 0 and the returned nodes take the
+    /// shares 1.., summed up by a chain of XORs.
+    pub fn recombine(
+        &mut self,
+        num_shares: u8,
+        nx: &NodeIndex,
+    ) -> Vec<(NodeIndex, NodePortId, NodePortId)> {
+        let xor = self.two_input_gate(GateType::Xor, false);
+        self.declassified.remove(nx);
+        self.graph[*nx].secure = false;
+        self.graph[*nx].node_type = xor.clone();
+        if num_shares <= 2 {
+            return vec![(*nx, 1, 0)];
+        }
+        let add_xor = |circuit: &mut Self| {
+            circuit.add_node(Node {
+                secure: true,
+                node_type: xor.clone(),
+                name: None,
+            })
+        };
+        let mut sum = add_xor(self);
+        let mut replicas = vec![(sum, 0, 0), (sum, 1, 0)];
+        for _ in 3..num_shares {
+            let next = add_xor(self);
+            self.connect(sum, 0, next, 0);
+            replicas.push((next, 1, 0));
+            sum = next;
+        }
+        self.connect(sum, 0, *nx, 1);
+        replicas
+    }
+
+    /// Wire the pre-shared input buffer `nx` to its share: the masking connected its input to
+    /// share 0 and 0 to the other shares of the `replicas`, so swap share 0 with its share.
+    pub(crate) fn place_share(
+        &mut self,
+        nx: NodeIndex,
+        replicas: &[(NodeIndex, NodePortId, NodePortId)],
+    ) {
+        self.classified.remove(&nx);
+        let share = self.preshared.remove(&nx).expect("not a pre-shared input") as usize;
+        if share == 0 {
+            return;
+        }
+        // the shares of every secret were checked against the masking order before masking
+        let (rx, _, _) = replicas[share - 1];
+        let input_edge = |circuit: &Self, nx: NodeIndex| {
+            let e = circuit
+                .graph
+                .edges_directed(nx, Direction::Incoming)
+                .next()
+                .expect("buffer without input");
+            (e.id(), e.source(), e.weight().0)
+        };
+        let (ex, sx, src_port) = input_edge(self, nx);
+        let (rex, zero, _) = input_edge(self, rx);
+        self.graph.remove_edge(ex);
+        self.graph.remove_edge(rex);
+        self.connect(zero, 0, nx, 0);
+        self.connect(sx, src_port, rx, 0);
+    }
+
+    /// Copies of `nx` computing the shares 1.. of its value, `nx` itself computes share 0.
+    /// Inversions and constants only apply to share 0: the other shares of an inverted gate are
+    /// not inverted and the other shares of a constant are 0.
     pub fn replicate_node(
         &mut self,
         num_shares: u8,
@@ -102,19 +289,16 @@ impl Circuit {
     ) -> Vec<(NodeIndex, NodePortId, NodePortId)> {
         let node = &self.graph[*nx];
         let node_name = node.name.clone();
-        let lr = node_name
-            .as_ref()
-            .and_then(|name| name.rfind("[").map(|i| name.split_at(i)));
-
-        fn share_name_lr(l: &str, r: &str, share: u8) -> String {
-            format!("{}_s{}{}", l, share, r)
-        }
-        let share_name = |share: u8| lr.map(|(l, r)| share_name_lr(l, r, share));
+        let share_name = |share: u8| node_name.as_ref().map(|name| share_name(name, share));
         let mut replicas = Vec::new();
         let node_type = node.node_type.clone();
         for share in 1..num_shares {
             let node_type = match node_type {
                 NodeType::Gate(gt, _) => NodeType::Gate(gt, false),
+                NodeType::Constant(_) => {
+                    replicas.push((self.const_node(false), 0, 0));
+                    continue;
+                }
                 _ => node_type.clone(),
             };
             let replica = Node {
@@ -123,10 +307,13 @@ impl Circuit {
                 secure: true,
             };
             let duplicate_node = self.add_node(replica);
+            if let Some(label) = self.labels.get(nx).cloned() {
+                self.labels.insert(duplicate_node, label);
+            }
             replicas.push((duplicate_node, 0, 0));
         }
-        if let Some((l, r)) = lr {
-            self.graph[*nx].name = Some(share_name_lr(l, r, 0));
+        if node_name.is_some() {
+            self.graph[*nx].name = share_name(0);
         }
         replicas
     }
@@ -169,14 +356,139 @@ impl Circuit {
         port_idx.and_then(|idx| inputs.get(&(idx as u8))).copied()
     }
 
+    /// Cell type used for a node type in exported netlists, preferring the name of the
+    /// library cell it was created from, then a cell of the library implementing it
+    pub fn cell_type_name(&self, node_type: &NodeType) -> Option<String> {
+        self.cell_type_names
+            .get(node_type)
+            .cloned()
+            .or_else(|| self.cell_library.cell_type_of(node_type).map(str::to_owned))
+            .or_else(|| node_type.cell_type_name())
+    }
+
+    /// number of input ports of a node, based on its connected inputs
+    pub fn num_input_ports(&self, nx: &NodeIndex) -> usize {
+        self.graph
+            .edges_directed(*nx, Direction::Incoming)
+            .map(|e| e.weight().1 as usize + 1)
+            .max()
+            .unwrap_or(0)
+    }
+
+    fn gate_port_names(&self, node_type: &NodeType, num_ports: usize, output: bool) -> Vec<String> {
+        let ordering_map = if output {
+            &self.output_ordering_map
+        } else {
+            &self.input_ordering_map
+        };
+        if let Some(names) = ordering_map.get(node_type) {
+            if names.len() >= num_ports {
+                return names.clone();
+            }
+        }
+        // pins of the library cell of a node type which is not in the netlist
+        let library_names = self
+            .cell_library
+            .cell_type_of(node_type)
+            .and_then(|cell_type| {
+                if output {
+                    self.cell_library.get_output_port_order(cell_type)
+                } else {
+                    self.cell_library.get_input_port_order(cell_type)
+                }
+            });
+        if let Some(names) = library_names.filter(|names| names.len() >= num_ports) {
+            return names.clone();
+        }
+        let defaults: Vec<&str> = match (node_type, output) {
+            (NodeType::Register, false) => vec!["C", "D"],
+            (NodeType::Register, true) => vec!["Q"],
+            (NodeType::Gate(GateType::Mux, _), false) => vec!["S", "A", "B"],
+            (NodeType::Blackbox(bb), _) => {
+                if let Some(bb) = self.blackboxes.get(bb) {
+                    let mut names = if output {
+                        bb.outputs.clone()
+                    } else {
+                        bb.inputs.clone()
+                    };
+                    names.sort();
+                    return names;
+                }
+                vec![]
+            }
+            (_, true) => vec!["Y"],
+            _ => vec![],
+        };
+        if defaults.len() >= num_ports {
+            return defaults.iter().map(|s| s.to_string()).collect();
+        }
+        if output {
+            (0..num_ports).map(|i| format!("Y{}", i)).collect()
+        } else if num_ports <= 26 {
+            (0..num_ports)
+                .map(|i| char::from(b'A' + i as u8).to_string())
+                .collect()
+        } else {
+            (0..num_ports).map(|i| format!("A{}", i)).collect()
+        }
+    }
+
+    /// Names of the input ports of a node, indexed by their `NodePortId`
+    pub fn input_port_names(&self, nx: &NodeIndex) -> Vec<String> {
+        let node_type = &self.graph[*nx].node_type;
+        let num_ports = self.num_input_ports(nx);
+        match *node_type {
+            NodeType::Gadget {
+                base_type,
+                invert,
+                num_shares,
+            } => {
+                let num_shares = num_shares as usize;
+                let num_in_ports = node_type.num_gadget_operands().unwrap_or(0);
+                let base_names =
+                    self.gate_port_names(&NodeType::Gate(base_type, invert), num_in_ports, false);
+                let num_random = num_ports.saturating_sub(num_shares * num_in_ports);
+                (0..num_shares)
+                    .flat_map(|s| {
+                        base_names
+                            .iter()
+                            .take(num_in_ports)
+                            .map(move |name| format!("{}_s{}", name, s))
+                    })
+                    .chain((0..num_random).map(|k| format!("R{}", k)))
+                    .collect()
+            }
+            _ => self.gate_port_names(node_type, num_ports, false),
+        }
+    }
+
+    /// Names of the output ports of a node, indexed by their `NodePortId`
+    pub fn output_port_names(&self, nx: &NodeIndex) -> Vec<String> {
+        let node_type = &self.graph[*nx].node_type;
+        match *node_type {
+            NodeType::Gadget {
+                base_type,
+                invert,
+                num_shares,
+            } => {
+                let base_names = self.gate_port_names(&NodeType::Gate(base_type, invert), 1, true);
+                (0..num_shares)
+                    .map(|s| format!("{}_s{}", base_names[0], s))
+                    .collect()
+            }
+            _ => self.gate_port_names(node_type, 1, true),
+        }
+    }
+
     fn convert_or(&mut self, nx: &NodeIndex) -> bool {
         let node = &mut self.graph[*nx];
         if !node.secure {
             return false;
         }
         match node.node_type {
-            NodeType::Gate(GateType::Or(_), ref mut inv) => {
-                *inv = !*inv;
+            // a | b = !(!a & !b)
+            NodeType::Gate(GateType::Or(n), inv) => {
+                node.node_type = NodeType::Gate(GateType::And(n), !inv);
             }
             _ => {
                 return false;
@@ -220,6 +532,116 @@ impl Circuit {
         }
     }
 
+    /// A secure MUX with a secure select and public data only needs share-wise gates:
+    /// `s ? b : a = a ^ (s & (a ^ b))`, where `a ^ b` is public.
+    fn convert_mux(&mut self, nx: NodeIndex) {
+        let node = &self.graph[nx];
+        let NodeType::Gate(GateType::Mux, invert) = node.node_type else {
+            return;
+        };
+        let inputs = self.node_inputs_map(&nx);
+        let secure = |port: NodePortId| inputs.get(&port).map(|&(sx, _)| self.graph[sx].secure);
+        if !node.secure
+            || [secure(0), secure(1), secure(2)] != [Some(true), Some(false), Some(false)]
+        {
+            return;
+        }
+        let [s, a, b] = [0, 1, 2].map(|port| inputs[&port]);
+        let incomings = self
+            .graph
+            .edges_directed(nx, Direction::Incoming)
+            .map(|e| e.id())
+            .collect_vec();
+        for e in incomings {
+            self.graph.remove_edge(e);
+        }
+        let diff = self.add_node(Node {
+            secure: false,
+            node_type: self.two_input_gate(GateType::Xor, false),
+            name: None,
+        });
+        self.connect(a.0, a.1, diff, 0);
+        self.connect(b.0, b.1, diff, 1);
+        let and = self.add_node(Node {
+            secure: true,
+            node_type: self.two_input_gate(GateType::And, false),
+            name: None,
+        });
+        self.connect(s.0, s.1, and, 0);
+        self.connect(diff, 0, and, 1);
+        self.graph[nx].node_type = self.two_input_gate(GateType::Xor, invert);
+        self.connect(a.0, a.1, nx, 0);
+        self.connect(and, 0, nx, 1);
+    }
+
+    pub fn convert_secure_muxes(&mut self) {
+        let current_nodes = self.graph.node_indices().collect_vec();
+        for nx in current_nodes {
+            self.convert_mux(nx);
+        }
+    }
+
+    /// Split a secure n-ary AND or OR gate into a balanced tree of 2-input gates of minimum
+    /// depth. Public inputs are combined first, so they need no gadgets.
+    fn decompose_gate(&mut self, nx: NodeIndex) {
+        let node = &self.graph[nx];
+        let gate_type: fn(u8) -> GateType = match node.node_type {
+            NodeType::Gate(GateType::And(_), _) if node.secure => GateType::And,
+            NodeType::Gate(GateType::Or(_), _) if node.secure => GateType::Or,
+            _ => return,
+        };
+        let NodeType::Gate(_, invert) = node.node_type else {
+            return;
+        };
+        let incomings = self
+            .graph
+            .edges_directed(nx, Direction::Incoming)
+            .map(|e| (e.id(), e.source(), *e.weight()))
+            .sorted_by_key(|&(_, sx, (_, dp))| (self.graph[sx].secure, dp))
+            .collect_vec();
+        if incomings.len() <= 2 {
+            return;
+        }
+        let mut level = incomings
+            .into_iter()
+            .map(|(e, sx, (sp, _))| {
+                self.graph.remove_edge(e);
+                (sx, sp)
+            })
+            .collect_vec();
+        let two_input = self.two_input_gate(gate_type, false);
+        while level.len() > 2 {
+            level = level
+                .chunks(2)
+                .map(|pair| match *pair {
+                    [(ax, ap), (bx, bp)] => {
+                        let gx = self.add_node(Node {
+                            secure: self.graph[ax].secure || self.graph[bx].secure,
+                            node_type: two_input.clone(),
+                            name: None,
+                        });
+                        self.connect(ax, ap, gx, 0);
+                        self.connect(bx, bp, gx, 1);
+                        (gx, 0)
+                    }
+                    _ => pair[0],
+                })
+                .collect();
+        }
+        // the original gate is the root of the tree
+        self.graph[nx].node_type = self.two_input_gate(gate_type, invert);
+        for (port, (sx, sp)) in level.into_iter().enumerate() {
+            self.connect(sx, sp, nx, port as NodePortId);
+        }
+    }
+
+    pub fn decompose_secure_gates(&mut self) {
+        let current_nodes = self.graph.node_indices().collect_vec();
+        for nx in current_nodes {
+            self.decompose_gate(nx);
+        }
+    }
+
     pub fn replace_gate_with_gadget(
         &mut self,
         num_shares: u8,
@@ -231,6 +653,10 @@ impl Circuit {
             .collect_vec();
 
         let has_insecure_input = !(input_secure.iter().cloned().all(identity));
+        let select_secure = self
+            .node_inputs_map(nx)
+            .get(&0)
+            .is_some_and(|&(sx, _)| self.graph[sx].secure);
 
         let num_in_ports = self.graph.edges_directed(*nx, Direction::Incoming).count() as NodePortId;
         let num_out_ports = 1 as NodePortId; // TODO
@@ -239,10 +665,15 @@ impl Circuit {
         match node.node_type {
             NodeType::Gate(gt, inv) => match gt {
                 GateType::And(_) if has_insecure_input => self.replicate_node(num_shares, nx),
-                GateType::Mux if !input_secure[0] => self.replicate_node(num_shares, nx),
+                // a public select picks the same input in every share
+                GateType::Mux if !select_secure => self.replicate_node(num_shares, nx),
                 _ => {
                     node.node_type = NodeType::Gadget {
-                        base_type: gt,
+                        base_type: match gt {
+                            GateType::And(_) => GateType::And(num_in_ports),
+                            GateType::Or(_) => GateType::Or(num_in_ports),
+                            _ => gt,
+                        },
                         invert: inv,
                         num_shares,
                     };
@@ -0,0 +1,245 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Display;
+
+use itertools::Itertools;
+use rand::Rng;
+
+use crate::logic::{Logic, LogicVec, LogicVector};
+
+use super::circuit_impl::share_name;
+use super::node::NodeType;
+use super::{Circuit, Error, NodeIndex, Simulator};
+
+/// An output of the masked circuit which differs from the original circuit
+#[derive(Debug, Clone, PartialEq)]
+pub struct Mismatch {
+    pub cycle: usize,
+    pub output: String,
+    pub expected: Logic,
+    /// XOR of the output shares
+    pub actual: Logic,
+    /// inputs of the original circuit in each cycle up to the mismatch
+    pub inputs: Vec<BTreeMap<String, Logic>>,
+    /// inputs of the masked circuit in each cycle up to the mismatch
+    pub masked_inputs: Vec<BTreeMap<String, Logic>>,
+}
+
+impl Display for Mismatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(
+            f,
+            "output {} in cycle {} is {} instead of {}",
+            self.output, self.cycle, self.actual, self.expected
+        )?;
+        for (cycle, (inputs, masked_inputs)) in
+            self.inputs.iter().zip(&self.masked_inputs).enumerate()
+        {
+            let fmt_inputs = |inputs: &BTreeMap<String, Logic>| {
+                inputs
+                    .iter()
+                    .map(|(name, v)| format!("{}={}", name, v))
+                    .join(" ")
+            };
+            writeln!(f, "cycle {}:", cycle)?;
+            writeln!(f, "  original: {}", fmt_inputs(inputs))?;
+            writeln!(f, "  masked:   {}", fmt_inputs(masked_inputs))?;
+        }
+        Ok(())
+    }
+}
+
+/// names of the shares of `name` in `masked`, or just `name` if it was not shared
+fn share_names(names: &HashMap<String, NodeIndex>, name: &str) -> Vec<String> {
+    let shares = (0..=u8::MAX)
+        .map(|s| share_name(name, s))
+        .take_while(|n| names.contains_key(n))
+        .collect_vec();
+    if shares.is_empty() {
+        vec![name.to_owned()]
+    } else {
+        shares
+    }
+}
+
+fn named_nodes(
+    circuit: &Circuit,
+    nodes: impl Iterator<Item = NodeIndex>,
+) -> HashMap<String, NodeIndex> {
+    nodes
+        .filter_map(|nx| circuit.graph[nx].name.clone().map(|name| (name, nx)))
+        .collect()
+}
+
+fn input_nodes(circuit: &Circuit) -> impl Iterator<Item = NodeIndex> + '_ {
+    circuit.graph.node_indices().filter(|&nx| {
+        matches!(
+            circuit.graph[nx].node_type,
+            NodeType::Input | NodeType::Control | NodeType::Random | NodeType::Reset
+        )
+    })
+}
+
+fn random_vec<R: Rng>(rng: &mut R, width: usize) -> LogicVec {
+    (0..width).map(|_| Logic::from(rng.gen::<bool>())).collect()
+}
+
+impl Circuit {
+    /// Check that `masked`, the result of masking this circuit, computes the same function.
+    /// Both circuits are simulated for `num_cycles` with `width` random stimuli in parallel.
+    /// Secure inputs are split into random shares, and the shares of each output are recombined
+    /// by XOR. Each output of `masked` is compared to the original output `output_latency`
+    /// cycles earlier. Returns the first output which differs where the original output is known.
+    pub fn check_equivalence<R: Rng>(
+        &self,
+        masked: &Circuit,
+        num_cycles: usize,
+        width: usize,
+        rng: &mut R,
+    ) -> Result<Option<Mismatch>, Error> {
+        let inputs = named_nodes(self, input_nodes(self));
+        let masked_inputs = named_nodes(masked, input_nodes(masked));
+        let masked_outputs = named_nodes(masked, masked.outputs.iter().copied());
+        // original input -> names of its shares
+        let input_shares: BTreeMap<_, _> = inputs
+            .keys()
+            .map(|name| (name.clone(), share_names(&masked_inputs, name)))
+            .collect();
+        // inputs which only exist in the masked circuit, e.g. fresh randomness
+        let masked_only = masked_inputs
+            .keys()
+            .filter(|name| !input_shares.values().flatten().contains(name))
+            .cloned()
+            .sorted()
+            .collect_vec();
+        let output_shares: BTreeMap<_, _> = named_nodes(self, self.outputs.iter().copied())
+            .into_keys()
+            .map(|name| {
+                let shares = share_names(&masked_outputs, &name);
+                let latency = masked_outputs
+                    .get(&shares[0])
+                    .map_or(0, |&ox| masked.output_latency(ox));
+                (name, (shares, latency))
+            })
+            .collect();
+        let max_latency = output_shares
+            .values()
+            .map(|&(_, latency)| latency)
+            .max()
+            .unwrap_or(0);
+
+        let mut sim = Simulator::new(self, width)?;
+        let mut masked_sim = Simulator::new(masked, width)?;
+        let mut stimuli = Vec::new();
+        let mut expected_outputs = Vec::new();
+        for cycle in 0..num_cycles + max_latency {
+            let mut stimulus = HashMap::new();
+            let mut masked_stimulus = HashMap::new();
+            for (name, shares) in input_shares.iter() {
+                let value = random_vec(rng, width);
+                let mut last_share = value.clone();
+                for share in shares.iter().skip(1) {
+                    let v = random_vec(rng, width);
+                    last_share = last_share.xor(&v);
+                    masked_stimulus.insert(share.clone(), v);
+                }
+                masked_stimulus.insert(shares[0].clone(), last_share);
+                stimulus.insert(name.clone(), value);
+            }
+            for name in masked_only.iter() {
+                masked_stimulus.insert(name.clone(), random_vec(rng, width));
+            }
+            let (outputs, _) = sim.step(&stimulus)?;
+            let (masked_outputs, _) = masked_sim.step(&masked_stimulus)?;
+            stimuli.push((stimulus, masked_stimulus));
+            expected_outputs.push(outputs);
+
+            for (name, (shares, latency)) in output_shares.iter() {
+                let Some(original_cycle) = cycle.checked_sub(*latency).filter(|&c| c < num_cycles)
+                else {
+                    continue;
+                };
+                let expected = &expected_outputs[original_cycle][name];
+                let recombined = shares
+                    .iter()
+                    .skip(1)
+                    .fold(masked_outputs[&shares[0]].buf(), |acc, share| {
+                        acc.xor(&masked_outputs[share])
+                    });
+                let Some(k) = (0..width).find(|&k| {
+                    let e = expected.get(k);
+                    matches!(e, Logic::Zero | Logic::One) && e != recombined.get(k)
+                }) else {
+                    continue;
+                };
+                let bits = |values: &HashMap<String, LogicVec>| {
+                    values
+                        .iter()
+                        .map(|(name, v)| (name.clone(), v.get(k)))
+                        .collect()
+                };
+                let (inputs, masked_inputs) = stimuli
+                    .iter()
+                    .map(|(stimulus, masked_stimulus)| (bits(stimulus), bits(masked_stimulus)))
+                    .unzip();
+                return Ok(Some(Mismatch {
+                    cycle,
+                    output: name.clone(),
+                    expected: expected.get(k),
+                    actual: recombined.get(k),
+                    inputs,
+                    masked_inputs,
+                }));
+            }
+        }
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    use super::super::node::GateType;
+    use super::*;
+    use crate::circuit::test_utils::simple_1;
+    use crate::circuit::Masking;
+
+    #[test]
+    fn masked_simple_1() -> Result<(), Error> {
+        let circuit = simple_1();
+        let mut rng = StdRng::seed_from_u64(1);
+        for order in 1..=2 {
+            let mut masked = circuit.clone();
+            masked.mask(order)?;
+            let mismatch = circuit.check_equivalence(&masked, 8, 64, &mut rng)?;
+            assert_eq!(mismatch, None, "order {}", order);
+        }
+
+        // break the masked circuit by inverting an XOR of shares
+        let mut masked = circuit.clone();
+        masked.mask(1)?;
+        let xor = masked
+            .graph
+            .node_indices()
+            .find(|&nx| {
+                let node = &masked.graph[nx];
+                node.secure && matches!(node.node_type, NodeType::Gate(GateType::Xor(_), _))
+            })
+            .unwrap();
+        if let NodeType::Gate(_, ref mut invert) = masked.graph[xor].node_type {
+            *invert = !*invert;
+        }
+        let mismatch = circuit
+            .check_equivalence(&masked, 8, 64, &mut rng)?
+            .expect("a mismatch");
+        assert!(mismatch.output.starts_with("out_data"));
+        assert!(mismatch.to_string().starts_with(&format!(
+            "output {} in cycle {}",
+            mismatch.output, mismatch.cycle
+        )));
+        assert_eq!(mismatch.inputs.len(), mismatch.cycle + 1);
+        assert!(mismatch.masked_inputs[0].contains_key("in_data_s1[0]"));
+        Ok(())
+    }
+}
@@ -0,0 +1,332 @@
+use itertools::Itertools;
+use petgraph::visit::EdgeRef;
+use petgraph::Direction;
+
+use super::gadget::{Gadget, Op, ShareNetwork, Wire};
+use super::node::{GateType, Node, NodePortId, NodeType};
+use super::{Circuit, NodeIndex};
+
+/// output port of a node
+type Signal = (NodeIndex, NodePortId);
+
+impl Circuit {
+    /// Replace every gadget node by the gates and registers of the gadget scheme of the circuit.
+    /// Fresh randomness comes from the random inputs of each gadget.
+    pub fn expand_gadgets(&mut self) {
+        let gadget = self.gadget.gadget();
+        let gadget_nodes = self
+            .graph
+            .node_indices()
+            .filter(|&nx| matches!(self.graph[nx].node_type, NodeType::Gadget { .. }))
+            .collect_vec();
+        for gx in gadget_nodes {
+            self.expand_gadget(gadget, gx);
+        }
+        // the random bits now feed gates, which do not get other bits
+        self.gadget_random_bits.clear();
+    }
+
+    fn expand_gadget(&mut self, gadget: &dyn Gadget, gx: NodeIndex) {
+        let NodeType::Gadget {
+            base_type,
+            invert,
+            num_shares,
+        } = self.graph[gx].node_type
+        else {
+            return;
+        };
+        let num_shares = num_shares as usize;
+        let inputs = self.node_inputs_map(&gx);
+        let num_operands = self.graph[gx].node_type.num_gadget_operands().unwrap_or(0);
+        // operand -> share -> driver
+        let mut operands = (0..num_operands)
+            .map(|i| {
+                (0..num_shares)
+                    .map(|s| {
+                        *inputs
+                            .get(&((s * num_operands + i) as NodePortId))
+                            .expect("unconnected gadget input")
+                    })
+                    .collect_vec()
+            })
+            .collect_vec();
+        let random = (0..self.gadget_randomness(&self.graph[gx].node_type))
+            .map(|k| {
+                *inputs
+                    .get(&((num_shares * num_operands + k) as NodePortId))
+                    .expect("unconnected gadget random input")
+            })
+            .collect_vec();
+        let net = gadget.and_network(num_shares);
+        let latency = gadget.latency();
+
+        let mut outputs = match base_type {
+            GateType::And(_) => self.expand_and(&net, latency, &operands, &random),
+            GateType::Or(_) => {
+                // a | b = !(!a & !b)
+                for operand in operands.iter_mut() {
+                    operand[0] = self.add_gate(NodeType::Gate(GateType::Buf, true), &[operand[0]]);
+                }
+                let mut outputs = self.expand_and(&net, latency, &operands, &random);
+                outputs[0] = self.add_gate(NodeType::Gate(GateType::Buf, true), &[outputs[0]]);
+                outputs
+            }
+            GateType::Mux => {
+                // s ? b : a = a ^ (s & (a ^ b))
+                let [s, a, b] = operands.as_slice() else {
+                    panic!("MUX gadget with {} inputs", num_operands);
+                };
+                let xor = self.two_input_gate(GateType::Xor, false);
+                let diff = a
+                    .iter()
+                    .zip(b)
+                    .map(|(&a, &b)| self.add_gate(xor.clone(), &[a, b]))
+                    .collect_vec();
+                let m = self.instantiate(&net, &[s.clone(), diff], &random);
+                let a = self.delay(a, latency);
+                a.iter()
+                    .zip(m)
+                    .map(|(&a, m)| self.add_gate(xor.clone(), &[a, m]))
+                    .collect()
+            }
+            GateType::Buf | GateType::Xor(_) => panic!("{} gadgets are not supported", base_type),
+        };
+        if invert {
+            outputs[0] = self.add_gate(NodeType::Gate(GateType::Buf, true), &[outputs[0]]);
+        }
+
+        let fanout = self
+            .graph
+            .edges_directed(gx, Direction::Outgoing)
+            .map(|e| (e.target(), *e.weight()))
+            .collect_vec();
+        for (dst, (src_port, dst_port)) in fanout {
+            let (sx, sp) = outputs[src_port as usize];
+            self.connect(sx, sp, dst, dst_port);
+        }
+        self.graph.remove_node(gx);
+    }
+
+    /// AND of all operands, by a chain of 2-input gadgets
+    fn expand_and(
+        &mut self,
+        net: &ShareNetwork,
+        latency: usize,
+        operands: &[Vec<Signal>],
+        random: &[Signal],
+    ) -> Vec<Signal> {
+        let mut acc = operands[0].clone();
+        for (k, operand) in operands.iter().enumerate().skip(1) {
+            // wait for the result of the previous gadgets
+            let operand = self.delay(operand, (k - 1) * latency);
+            let random = &random[(k - 1) * net.num_random..k * net.num_random];
+            acc = self.instantiate(net, &[acc, operand], random);
+        }
+        acc
+    }
+
+    /// instantiate the network of a gadget with the given shares of its inputs
+    fn instantiate(
+        &mut self,
+        net: &ShareNetwork,
+        operands: &[Vec<Signal>],
+        random: &[Signal],
+    ) -> Vec<Signal> {
+        let and = self.two_input_gate(GateType::And, false);
+        let xor = self.two_input_gate(GateType::Xor, false);
+        let clock = self.gadget_clock();
+        let mut values: Vec<Signal> = Vec::with_capacity(net.ops.len());
+        let wire = |values: &[Signal], w: &Wire| match *w {
+            Wire::Input { input, share } => operands[input][share],
+            Wire::Random(k) => random[k],
+            Wire::Op(i) => values[i],
+        };
+        for op in net.ops.iter() {
+            let value = match op {
+                Op::And(a, b) => {
+                    let inputs = [wire(&values, a), wire(&values, b)];
+                    self.add_gate(and.clone(), &inputs)
+                }
+                Op::Xor(a, b) => {
+                    let inputs = [wire(&values, a), wire(&values, b)];
+                    self.add_gate(xor.clone(), &inputs)
+                }
+                Op::Not(a) => {
+                    let inputs = [wire(&values, a)];
+                    self.add_gate(NodeType::Gate(GateType::Buf, true), &inputs)
+                }
+                Op::Register(a) => {
+                    let inputs = [clock, wire(&values, a)];
+                    self.add_gate(NodeType::Register, &inputs)
+                }
+            };
+            values.push(value);
+        }
+        net.outputs.iter().map(|w| wire(&values, w)).collect()
+    }
+
+    /// shares delayed by `cycles` registers
+    fn delay(&mut self, shares: &[Signal], cycles: usize) -> Vec<Signal> {
+        let clock = self.gadget_clock();
+        shares
+            .iter()
+            .map(|&share| {
+                (0..cycles).fold(share, |d, _| self.add_gate(NodeType::Register, &[clock, d]))
+            })
+            .collect()
+    }
+
+    /// a secure node driven by `inputs`, in the order of its input ports
+    fn add_gate(&mut self, node_type: NodeType, inputs: &[Signal]) -> Signal {
+        let nx = self.add_node(Node {
+            secure: true,
+            node_type,
+            name: None,
+        });
+        for (port, &(sx, sp)) in inputs.iter().enumerate() {
+            self.connect(sx, sp, nx, port as NodePortId);
+        }
+        (nx, 0)
+    }
+
+    /// 2-input gate, using the variant of the library cells of the circuit
+    pub(crate) fn two_input_gate(&self, gate_type: fn(u8) -> GateType, invert: bool) -> NodeType {
+        let unsized_gate = NodeType::Gate(gate_type(0), invert);
+        if self.cell_type_names.contains_key(&unsized_gate) {
+            unsized_gate
+        } else {
+            NodeType::Gate(gate_type(2), invert)
+        }
+    }
+
+    /// clock of the gadget registers
+    pub(crate) fn gadget_clock(&mut self) -> Signal {
+        let clock = match self.clocks.iter().min() {
+            Some(&clock) => clock,
+            None => self.add_node(Node {
+                secure: false,
+                node_type: NodeType::Clock,
+                name: Some("clk".to_owned()),
+            }),
+        };
+        (clock, 0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+    use strum::IntoEnumIterator;
+
+    use super::*;
+    use crate::circuit::test_utils::simple_1;
+    use crate::circuit::{GadgetKind, Masking, Simulator};
+    use crate::logic::{Logic, LogicVec};
+
+    /// a circuit computing `y = gate(a, b, c)`, with secure inputs
+    fn gate_circuit(node_type: NodeType) -> Circuit {
+        let mut circuit = Circuit::default();
+        let clock = circuit.add_test_node(false, NodeType::Clock, "clk");
+        let gate = circuit.add_test_node(false, node_type, "");
+        for (port, name) in ["a", "b", "c"].into_iter().enumerate() {
+            let input = circuit.add_test_node(true, NodeType::Input, name);
+            circuit.connect(input, 0, gate, port as NodePortId);
+        }
+        let output = circuit.add_test_node(false, NodeType::Output, "y");
+        circuit.connect(gate, 0, output, 0);
+        // keep the clock used
+        let reg = circuit.add_test_node(false, NodeType::Register, "");
+        circuit.connect(clock, 0, reg, 0);
+        circuit
+    }
+
+    #[test]
+    fn expanded_gadgets() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let width = 64;
+        let gates = [
+            NodeType::Gate(GateType::And(3), false),
+            NodeType::Gate(GateType::Or(3), true),
+            NodeType::Gate(GateType::Mux, false),
+        ];
+        for kind in GadgetKind::iter() {
+            let gadget = kind.gadget();
+            let num_shares = gadget.num_shares(2);
+            for node_type in gates.iter() {
+                let circuit = gate_circuit(node_type.clone());
+                let mut masked = circuit.clone();
+                masked.mask_with(2, kind).unwrap();
+                masked.expand_gadgets();
+                assert!(masked
+                    .graph
+                    .node_weights()
+                    .all(|node| !matches!(node.node_type, NodeType::Gadget { .. })));
+
+                // the latency of a chain of 2-input gadgets
+                let latency = match node_type {
+                    NodeType::Gate(GateType::Mux, _) => gadget.latency(),
+                    _ => 2 * gadget.latency(),
+                };
+                // hold random inputs until the result is available
+                let random_vec = |rng: &mut StdRng| -> LogicVec {
+                    (0..width).map(|_| Logic::from(rng.gen::<bool>())).collect()
+                };
+                let values = ["a", "b", "c"].map(|name| (name, random_vec(&mut rng)));
+                let mut stimulus = HashMap::new();
+                for (name, v) in values.iter() {
+                    let mut s0 = v.clone();
+                    for s in 1..num_shares {
+                        let share = random_vec(&mut rng);
+                        s0 = s0.xor(&share);
+                        stimulus.insert(format!("{}_s{}", name, s), share);
+                    }
+                    stimulus.insert(format!("{}_s0", name), s0);
+                }
+                let mut sim = Simulator::new(&masked, width).unwrap();
+                let mut outputs = Default::default();
+                for _ in 0..=latency {
+                    for name in masked
+                        .inputs
+                        .iter()
+                        .filter_map(|&nx| masked.graph[nx].name.clone())
+                    {
+                        if name.starts_with("rand[") {
+                            stimulus.insert(name, random_vec(&mut rng));
+                        }
+                    }
+                    (outputs, _) = sim.step(&stimulus).unwrap();
+                }
+                let y = (1..num_shares).fold(outputs["y_s0"].clone(), |y, s| {
+                    y.xor(&outputs[&format!("y_s{}", s)])
+                });
+
+                let [a, b, c] = values.map(|(_, v)| v);
+                let expected = match node_type {
+                    NodeType::Gate(GateType::And(_), _) => a.and(&b).and(&c),
+                    NodeType::Gate(GateType::Or(_), _) => a.or(&b).or(&c).not(),
+                    _ => a.mux(&b, &c),
+                };
+                assert_eq!(y, expected, "{} {}", kind, node_type);
+            }
+        }
+    }
+
+    #[test]
+    fn expanded_simple_1() -> Result<(), crate::circuit::Error> {
+        let circuit = simple_1();
+        let mut masked = circuit.clone();
+        // ISW has no registers, so the expanded circuit has the timing of the original
+        masked.mask_with(1, GadgetKind::Isw)?;
+        masked.expand_gadgets();
+        assert!(masked
+            .inputs
+            .iter()
+            .any(|&nx| masked.graph[nx].name.as_deref() == Some("rand[0]")));
+        let mut rng = StdRng::seed_from_u64(0);
+        assert_eq!(circuit.check_equivalence(&masked, 8, 64, &mut rng)?, None);
+        Ok(())
+    }
+}
@@ -1,14 +1,22 @@
 use std::collections::HashMap;
 use std::path::Path;
+use std::str::FromStr;
+
+use itertools::Itertools;
+use petgraph::Direction;
+use simple_error::SimpleError;
 
 use crate::circuit::node::Blackbox;
+use crate::config::Config;
 use crate::netlist::json_netlist::{
     AttributeVal, BitVal, Netlist, PortDirection, SignalId,
 };
+use crate::netlist::verilog;
 use crate::utils::MapToVec;
 
 use super::cell_library::CellLibrary;
-use super::{Circuit, Error, NodeBuilder, NodeIndex, NodePortId, NodeType};
+use super::node::GateType;
+use super::{Circuit, Error, Label, NodeBuilder, NodeIndex, NodePortId, NodeType};
 
 use std::{fs::File, io::BufReader};
 
@@ -25,15 +33,98 @@ impl NetlistAndLibrary {
         }
     }
 
+    /// Load a Yosys JSON netlist, or a structural Verilog netlist if the extension is `.v` or `.sv`
     pub fn from_path<P: AsRef<Path>>(netlist_path: P) -> Result<Self, Error> {
+        Self::from_path_and_library(netlist_path, CellLibrary::new())
+    }
+
+    /// Load a netlist (see [NetlistAndLibrary::from_path]) of cells from `cell_library`
+    pub fn from_path_and_library<P: AsRef<Path>>(
+        netlist_path: P,
+        cell_library: CellLibrary,
+    ) -> Result<Self, Error> {
+        let is_verilog = netlist_path
+            .as_ref()
+            .extension()
+            .is_some_and(|ext| ext == "v" || ext == "sv");
+        if is_verilog {
+            return Self::from_verilog_path(netlist_path, cell_library);
+        }
         let file = File::open(netlist_path)?;
         let reader = BufReader::new(file);
         let netlist = Netlist::from_reader(reader)?;
         Ok(NetlistAndLibrary {
             netlist,
-            cell_library: CellLibrary::new(),
+            cell_library,
         })
     }
+
+    /// Load the netlist and cell library of a project config, and apply its attributes
+    pub fn from_config(config: &Config) -> Result<Self, Error> {
+        let cell_library = match config.liberty_path() {
+            Some(path) => CellLibrary::from_liberty_path(path)?,
+            None => CellLibrary::new(),
+        };
+        let mut netlist_and_library =
+            Self::from_path_and_library(config.netlist_path()?, cell_library)?;
+        config.apply_attributes(&mut netlist_and_library.netlist)?;
+        Ok(netlist_and_library)
+    }
+
+    pub fn from_verilog_path<P: AsRef<Path>>(
+        netlist_path: P,
+        cell_library: CellLibrary,
+    ) -> Result<Self, Error> {
+        let netlist = verilog::parse_file(netlist_path, |cell_type, port| {
+            cell_library.port_direction(cell_type, port)
+        })?;
+        Ok(NetlistAndLibrary {
+            netlist,
+            cell_library,
+        })
+    }
+}
+
+/// whether a net or cell is a declassification point
+fn is_declassified(attributes: &HashMap<String, AttributeVal>) -> bool {
+    matches!(attributes.get("MASQ"), Some(AttributeVal::String(s)) if s.eq_ignore_ascii_case("declassify"))
+}
+
+/// security label of a net or cell, if its MASQ attribute is a secret label like `secure:key`
+fn masq_label(attributes: &HashMap<String, AttributeVal>) -> Option<Label> {
+    match attributes.get("MASQ") {
+        Some(AttributeVal::String(s)) => Label::from_str(&s.to_lowercase())
+            .ok()
+            .filter(|label| !label.is_public()),
+        _ => None,
+    }
+}
+
+/// share index and label of an input delivering a share of a secret, from a MASQ attribute
+/// `share:1` for share 1 of the default secret or `share:1:key` for share 1 of the secret `key`
+fn masq_share(attributes: &HashMap<String, AttributeVal>) -> Option<(u8, Label)> {
+    let Some(AttributeVal::String(s)) = attributes.get("MASQ") else {
+        return None;
+    };
+    let s = s.to_lowercase();
+    let mut fields = s.strip_prefix("share:")?.splitn(2, ':');
+    let share = fields.next()?.parse().ok()?;
+    let label = match fields.next() {
+        Some(secrets) => Label::from_str(&format!("secure:{}", secrets)).ok()?,
+        None => Label::from_str("secure").ok()?,
+    };
+    Some((share, label))
+}
+
+impl Circuit {
+    /// output ports of `nx` which drive other nodes
+    fn used_output_ports(&self, nx: NodeIndex) -> Vec<NodePortId> {
+        self.graph
+            .edges_directed(nx, Direction::Outgoing)
+            .map(|e| e.weight().0)
+            .unique()
+            .collect_vec()
+    }
 }
 
 impl TryFrom<&NetlistAndLibrary> for Circuit {
@@ -51,6 +142,7 @@ impl TryFrom<&NetlistAndLibrary> for Circuit {
 
         let cl = &nl_cl.cell_library;
         let netlist = &nl_cl.netlist;
+        circuit.cell_library = cl.clone();
 
         for (bb_name, module) in netlist.modules.iter().filter(|(_, m)| m.is_blackbox()) {
             let bb = Blackbox {
@@ -61,13 +153,15 @@ impl TryFrom<&NetlistAndLibrary> for Circuit {
         }
 
         let (top_name, module) = netlist.get_top().expect("No top module was found!");
-        println!("Top module: {}", top_name);
+        log::debug!("Top module: {}", top_name);
         circuit.name = top_name.clone();
         // 1-to-1 mapping of signal -> (src_node_id, src_node_port)
         let mut sig_driver = HashMap::<SignalId, (NodeIndex, NodePortId)>::default();
         // mapping of node_id -> Vec of input bits in the order of node's input ports
         let mut node_to_inbits = MapToVec::<NodeIndex, BitVal>::default();
         // FIXME for all modules?
+        // inputs delivering a share of a secret, with the share index and label
+        let mut preshared_inputs = Vec::new();
         // add IO nodes:
         for (port_name, port) in module.ports.iter() {
             let net = module
@@ -78,22 +172,29 @@ impl TryFrom<&NetlistAndLibrary> for Circuit {
             match port.direction {
                 PortDirection::Input => {
                     for (idx, bit) in net.bits.iter().enumerate() {
-                        let (node_type, is_secure) = match net.attributes.get("MASQ") {
+                        let (node_type, label) = match net.attributes.get("MASQ") {
                             Some(AttributeVal::String(s)) => match s.to_lowercase().as_str() {
-                                "secure" => (NodeType::Input, true),
-                                "clock" => (NodeType::Clock, false),
-                                "reset" => (NodeType::Reset, false),
-                                _ => (NodeType::Input, false),
+                                "constant" => (NodeType::Control, None),
+                                "random" => (NodeType::Random, None),
+                                "clock" => (NodeType::Clock, None),
+                                "reset" => (NodeType::Reset, None),
+                                s if s.starts_with("share:") => (NodeType::Input, None),
+                                s => (NodeType::Input, Label::from_str(s).ok()),
                             },
-                            _ => (NodeType::Input, false),
+                            _ => (NodeType::Input, None),
                         };
                         let node = NodeBuilder::default()
                             .node_type(node_type)
-                            .secure(is_secure)
                             .name(io_node_name(port_name, w, idx))
                             .build()
                             .unwrap();
                         let node_id = circuit.add_node(node);
+                        if let Some(label) = label {
+                            circuit.set_input_label(node_id, label);
+                        }
+                        if let Some(share) = masq_share(&net.attributes) {
+                            preshared_inputs.push((node_id, share));
+                        }
 
                         match bit {
                             BitVal::Signal(sig) => {
@@ -124,8 +225,17 @@ impl TryFrom<&NetlistAndLibrary> for Circuit {
                 t => panic!("{:?} is not supported", t),
             };
         }
+        let mut declassified_cells = Vec::new();
+        let mut classified_cells = Vec::new();
         // add gates and registers:
         for (cell_name, cell) in module.cells.iter() {
+            if let Some(reason) = cl.unsupported_reason(&cell.cell_type) {
+                return Err(SimpleError::new(format!(
+                    "cell {} of type {} can not be masked: {}",
+                    cell_name, cell.cell_type, reason
+                ))
+                .into());
+            }
             let node_type = NodeType::try_from((cl, &cell.cell_type))?;
             let node = NodeBuilder::default()
                 .node_type(node_type.clone())
@@ -133,21 +243,54 @@ impl TryFrom<&NetlistAndLibrary> for Circuit {
                 .build()
                 .unwrap();
             let node_id = circuit.add_node(node);
+            if is_declassified(&cell.attributes) {
+                declassified_cells.push(node_id);
+            }
+            if let Some(label) = masq_label(&cell.attributes) {
+                classified_cells.push((node_id, label));
+            }
             //
-            for ((_, bits), out_port_id) in cell.output_ports().zip(0..) {
+            let mut out_names = Vec::new();
+            for ((name, bits), out_port_id) in cell.output_ports().zip(0..) {
                 for bit in bits {
                     if let BitVal::Signal(sig) = bit {
                         sig_driver.insert(*sig, (node_id, out_port_id));
                     }
                 }
+                out_names.push(name.clone());
             }
+            circuit
+                .output_ordering_map
+                .entry(node_type.clone())
+                .or_insert(out_names);
+            circuit
+                .cell_type_names
+                .entry(node_type.clone())
+                .or_insert_with(|| cell.cell_type.clone());
 
             // if we have an ordering in CellLibrary use that
             if let Some(order) = cl.get_input_port_order(&cell.cell_type) {
                 for port_name in order {
-                    let bits = cell.connections.get(port_name).unwrap();
-                    assert!(bits.len() == 1);
-                    node_to_inbits.append(node_id, bits[0]);
+                    let bit = match cell.connections.get(port_name).map(Vec::as_slice) {
+                        Some([bit]) => bit,
+                        Some(bits) => {
+                            return Err(SimpleError::new(format!(
+                                "pin {} of cell {} is connected to {} bits instead of 1",
+                                port_name,
+                                cell_name,
+                                bits.len()
+                            ))
+                            .into())
+                        }
+                        None => {
+                            return Err(SimpleError::new(format!(
+                                "pin {} of cell {} is not connected",
+                                port_name, cell_name
+                            ))
+                            .into())
+                        }
+                    };
+                    node_to_inbits.append(node_id, *bit);
                 }
                 circuit.input_ordering_map.insert(node_type, order.clone());
             } else {
@@ -174,6 +317,265 @@ impl TryFrom<&NetlistAndLibrary> for Circuit {
                 circuit.connect(src_node, src_port, *dst_node, dst_port);
             }
         }
+        // declassify the outputs of cells and the drivers of nets
+        for nx in declassified_cells {
+            if circuit.graph[nx].node_type == NodeType::Gate(GateType::Buf, false) {
+                circuit.declassified.insert(nx);
+                continue;
+            }
+            for port in circuit.used_output_ports(nx) {
+                circuit.declassify(nx, port);
+            }
+        }
+        for net in module
+            .netnames
+            .values()
+            .filter(|net| is_declassified(&net.attributes))
+        {
+            for bit in net.bits.iter() {
+                if let BitVal::Signal(sig) = bit {
+                    if let Some(&(nx, port)) = sig_driver.get(sig) {
+                        circuit.declassify(nx, port);
+                    }
+                }
+            }
+        }
+        for (nx, (share, label)) in preshared_inputs {
+            circuit.preshare(nx, share, label);
+        }
+        // secrets inside the circuit: the outputs of cells and the drivers of nets, except for
+        // the input ports labeled above
+        for (nx, label) in classified_cells {
+            for port in circuit.used_output_ports(nx) {
+                circuit.classify(nx, port, label.clone());
+            }
+        }
+        for (net_name, net) in module.netnames.iter() {
+            let is_input = module
+                .ports
+                .get(net_name)
+                .is_some_and(|port| port.direction == PortDirection::Input);
+            let Some(label) = masq_label(&net.attributes).filter(|_| !is_input) else {
+                continue;
+            };
+            for bit in net.bits.iter() {
+                if let BitVal::Signal(sig) = bit {
+                    if let Some(&(nx, port)) = sig_driver.get(sig) {
+                        circuit.classify(nx, port, label.clone());
+                    }
+                }
+            }
+        }
         Ok(circuit)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    use super::*;
+    use crate::circuit::Masking;
+
+    #[test]
+    fn verilog_netlist_matches_json() -> Result<(), Error> {
+        let netlist_path = |ext: &str| {
+            format!(
+                "{}/tests/Xoodyak/mkPerm_netlist.{}",
+                env!("CARGO_MANIFEST_DIR"),
+                ext
+            )
+        };
+        let from_json = Circuit::try_from(&NetlistAndLibrary::from_path(netlist_path("json"))?)?;
+        let from_verilog = Circuit::try_from(&NetlistAndLibrary::from_path(netlist_path("v"))?)?;
+        assert_eq!(from_verilog.name, from_json.name);
+        assert_eq!(from_verilog.stats(), from_json.stats());
+        Ok(())
+    }
+
+    #[test]
+    fn load_config() -> Result<(), Error> {
+        let config_path = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/Xoodyak/mkperm.toml");
+        let config = Config::from_path(config_path)?;
+        let circuit = Circuit::try_from(&NetlistAndLibrary::from_config(&config)?)?;
+        assert_eq!(circuit.name, "mkPerm");
+        assert_eq!(circuit.secure_inputs().len(), 384);
+        assert_eq!(circuit.control_inputs().len(), 12);
+        assert_eq!(circuit.clocks.len(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn declassify_output() -> Result<(), Error> {
+        let base_dir = Path::new(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/hdl/simple"));
+        let toml = std::fs::read_to_string(base_dir.join("simple_1.toml"))?.replace(
+            "[rtl.attributes.MASQ]",
+            "[rtl.attributes.MASQ]\nout_data = \"declassify\"",
+        );
+        let config = Config::from_toml_str(&toml, base_dir)?;
+        let circuit = Circuit::try_from(&NetlistAndLibrary::from_config(&config)?)?;
+        assert_eq!(circuit.declassified().len(), 2);
+
+        let mut masked = circuit.clone();
+        masked.mask(1)?;
+        // the data is recombined after its registers and leaves the circuit in the clear
+        assert!(masked.declassified().is_empty());
+        assert!(masked.secure_outputs().is_empty());
+        assert_eq!(masked.latency(), 1);
+        let mut rng = StdRng::seed_from_u64(0);
+        assert_eq!(circuit.check_equivalence(&masked, 8, 64, &mut rng)?, None);
+        masked.expand_gadgets();
+        assert_eq!(circuit.check_equivalence(&masked, 8, 64, &mut rng)?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn classify_internal_nets() -> Result<(), Error> {
+        let base_dir = Path::new(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/hdl/simple"));
+        // the data arrives in the clear and becomes a secret after the AND and XOR of its bits
+        let toml = std::fs::read_to_string(base_dir.join("simple_1.toml"))?
+            .replace("in_data\" = \"secure\"", "in_data\" = \"public\"")
+            .replace(
+                "[rtl.attributes.MASQ]",
+                "[rtl.attributes.MASQ]\n\"*new_n2?_\" = \"secure:key\"",
+            );
+        let config = Config::from_toml_str(&toml, base_dir)?;
+        let mut circuit = Circuit::try_from(&NetlistAndLibrary::from_config(&config)?)?;
+        assert!(circuit.secure_inputs().is_empty());
+        assert_eq!(circuit.classified().len(), 2);
+        circuit.propagate_secure();
+        assert_eq!(
+            circuit.secret_report().0["key"].outputs,
+            ["out_data[0]", "out_data[1]"]
+        );
+
+        for order in 1..=2 {
+            let mut masked = circuit.clone();
+            masked.mask(order)?;
+            assert!(masked.classified().is_empty());
+            assert_eq!(masked.secure_outputs().len(), 2 * (order as usize + 1));
+            // fresh shares for the two secrets
+            assert_eq!(masked.random_inputs().len(), 2 * order as usize);
+            let mut rng = StdRng::seed_from_u64(0);
+            assert_eq!(circuit.check_equivalence(&masked, 8, 64, &mut rng)?, None);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn unsupported_cells_and_pins() -> Result<(), Error> {
+        let library = || {
+            CellLibrary::from_liberty_str(
+                "test.lib",
+                r#"library(test) {
+                    cell(DFFR) {
+                        ff(IQ, IQN) { clocked_on: CK; next_state: D; clear: "!RN"; }
+                        pin(CK) { direction: input; }
+                        pin(D) { direction: input; }
+                        pin(RN) { direction: input; }
+                        pin(Q) { direction: output; function: "IQ"; }
+                    }
+                    cell(EDFF) {
+                        ff(IQ, IQN) { clocked_on: CK; next_state: "(D & E) | (IQ & !E)"; }
+                        pin(CK) { direction: input; }
+                        pin(D) { direction: input; }
+                        pin(E) { direction: input; }
+                        pin(Q) { direction: output; function: "IQ"; }
+                    }
+                }"#,
+            )
+        };
+        let cell_library = library()?;
+        assert_eq!(
+            cell_library.unsupported_reason("DFFR"),
+            Some("flip-flop with asynchronous clear")
+        );
+        assert!(cell_library
+            .unsupported_reason("EDFF")
+            .unwrap()
+            .contains("enable"));
+        assert_eq!(cell_library.unsupported_reason("DFF"), None);
+
+        let load = |cell: &str| -> Result<Circuit, Error> {
+            let cell_library = library()?;
+            let src = format!(
+                "module top(clk, d, q);\n  input clk;\n  input d;\n  output q;\n  {}\nendmodule\n",
+                cell
+            );
+            let netlist = verilog::parse_str("top.v", &src, |cell_type, port| {
+                cell_library.port_direction(cell_type, port)
+            })?;
+            Circuit::try_from(&NetlistAndLibrary {
+                netlist,
+                cell_library,
+            })
+        };
+        load("DFF r (.C(clk), .D(d), .Q(q));")?;
+        let error = load("DFFR r (.CK(clk), .D(d), .RN(d), .Q(q));").unwrap_err();
+        assert!(error
+            .to_string()
+            .contains("cell r of type DFFR can not be masked"));
+        let error = load("DFF r (.C(clk), .Q(q));").unwrap_err();
+        assert_eq!(error.to_string(), "pin D of cell r is not connected");
+        Ok(())
+    }
+
+    #[test]
+    fn library_cells_of_added_gates() -> Result<(), Error> {
+        let cell_library = CellLibrary::from_liberty_str(
+            "test.lib",
+            r#"library(test) {
+                cell(AN2) {
+                    pin(A1) { direction: input; }
+                    pin(A2) { direction: input; }
+                    pin(Z) { direction: output; function: "A1 & A2"; }
+                }
+                cell(XO2) {
+                    pin(A1) { direction: input; }
+                    pin(A2) { direction: input; }
+                    pin(Z) { direction: output; function: "A1 ^ A2"; }
+                }
+                cell(INV) {
+                    pin(I) { direction: input; }
+                    pin(ZN) { direction: output; function: "!I"; }
+                }
+                cell(DFQ) {
+                    ff(IQ, IQN) { clocked_on: CK; next_state: D; }
+                    pin(CK) { direction: input; }
+                    pin(D) { direction: input; }
+                    pin(Q) { direction: output; function: "IQ"; }
+                }
+            }"#,
+        )?;
+        let src = "module top(clk, a, b, y);\n  (* MASQ = \"clock\" *)\n  input clk;\n  \
+            (* MASQ = \"secure\" *)\n  input a;\n  (* MASQ = \"secure\" *)\n  input b;\n  \
+            output y;\n  AN2 g (.A1(a), .A2(b), .Z(y));\nendmodule\n";
+        let netlist = verilog::parse_str("top.v", src, |cell_type, port| {
+            cell_library.port_direction(cell_type, port)
+        })?;
+        let mut circuit = Circuit::try_from(&NetlistAndLibrary {
+            netlist,
+            cell_library,
+        })?;
+        circuit.mask(1)?;
+        circuit.expand_gadgets();
+        // the XORs and registers of the gadget are cells of the library, with its pins
+        let netlist = Netlist::try_from(&circuit)?;
+        let pins = netlist.modules["top"]
+            .cells
+            .values()
+            .map(|cell| {
+                (
+                    cell.cell_type.as_str(),
+                    cell.connections.keys().sorted().join(","),
+                )
+            })
+            .counts();
+        assert_eq!(pins[&("AN2", "A1,A2,Z".to_owned())], 4);
+        assert_eq!(pins[&("XO2", "A1,A2,Z".to_owned())], 4);
+        assert_eq!(pins[&("DFQ", "CK,D,Q".to_owned())], 4);
+        assert_eq!(pins.values().sum::<usize>(), 12);
+        Ok(())
+    }
+}
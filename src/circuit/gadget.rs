@@ -0,0 +1,607 @@
+//! Masked AND gadgets, described as networks of operations on shares
+
+use itertools::Itertools;
+
+/// Security property of a gadget at its masking order
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, strum::Display)]
+pub enum Security {
+    /// probing secure, also with glitches, but not composable
+    #[strum(serialize = "probing")]
+    Probing,
+    /// strong non-interference, without glitches
+    #[strum(serialize = "SNI")]
+    Sni,
+    /// probe-isolating non-interference, also with glitches (trivially composable)
+    #[strum(serialize = "PINI")]
+    Pini,
+}
+
+/// A signal in the share network of a gadget
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Wire {
+    /// share `share` of input `input` (0 or 1)
+    Input { input: usize, share: usize },
+    /// fresh random bit
+    Random(usize),
+    /// result of the operation with this index
+    Op(usize),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Op {
+    And(Wire, Wire),
+    Xor(Wire, Wire),
+    Not(Wire),
+    Register(Wire),
+}
+
+/// Gate-level implementation of a gadget. Operations only use the results of earlier operations.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ShareNetwork {
+    pub ops: Vec<Op>,
+    /// output shares
+    pub outputs: Vec<Wire>,
+    pub num_random: usize,
+}
+
+impl ShareNetwork {
+    fn op(&mut self, op: Op) -> Wire {
+        self.ops.push(op);
+        Wire::Op(self.ops.len() - 1)
+    }
+    fn and(&mut self, a: Wire, b: Wire) -> Wire {
+        self.op(Op::And(a, b))
+    }
+    fn xor(&mut self, a: Wire, b: Wire) -> Wire {
+        self.op(Op::Xor(a, b))
+    }
+    fn not(&mut self, a: Wire) -> Wire {
+        self.op(Op::Not(a))
+    }
+    fn reg(&mut self, a: Wire) -> Wire {
+        self.op(Op::Register(a))
+    }
+    fn random(&mut self) -> Wire {
+        self.num_random += 1;
+        Wire::Random(self.num_random - 1)
+    }
+    fn xor_all(&mut self, wires: impl IntoIterator<Item = Wire>) -> Wire {
+        let mut wires = wires.into_iter();
+        let first = wires.next().expect("XOR of nothing");
+        wires.fold(first, |acc, w| self.xor(acc, w))
+    }
+
+    /// shares of an input
+    fn inputs(input: usize, num_shares: usize) -> Vec<Wire> {
+        (0..num_shares)
+            .map(|share| Wire::Input { input, share })
+            .collect()
+    }
+
+    /// symmetric fresh randomness `r[i][j] == r[j][i]` for `i != j`
+    fn random_pairs(&mut self, num_shares: usize) -> Vec<Vec<Option<Wire>>> {
+        let mut r = vec![vec![None; num_shares]; num_shares];
+        for (i, j) in (0..num_shares).tuple_combinations() {
+            let rij = self.random();
+            r[i][j] = Some(rij);
+            r[j][i] = Some(rij);
+        }
+        r
+    }
+
+    /// DOM-indep multiplication of `xs` and `ys`, with all products registered
+    fn dom_indep(&mut self, xs: &[Wire], ys: &[Wire]) -> Vec<Wire> {
+        let n = xs.len();
+        let r = self.random_pairs(n);
+        (0..n)
+            .map(|i| {
+                let terms = (0..n)
+                    .map(|j| {
+                        let p = self.and(xs[i], ys[j]);
+                        match r[i][j] {
+                            Some(rij) => {
+                                let t = self.xor(p, rij);
+                                self.reg(t)
+                            }
+                            None => self.reg(p),
+                        }
+                    })
+                    .collect_vec();
+                self.xor_all(terms)
+            })
+            .collect()
+    }
+}
+
+/// A masked AND gadget scheme
+pub trait Gadget {
+    fn name(&self) -> &'static str;
+    fn security(&self) -> Security;
+    /// number of shares of a circuit masked with this gadget at masking order `order`
+    fn num_shares(&self, order: usize) -> usize {
+        order + 1
+    }
+    /// number of shares of each input
+    fn input_shares(&self, num_shares: usize) -> usize {
+        num_shares
+    }
+    /// number of shares of the output
+    fn output_shares(&self, num_shares: usize) -> usize {
+        num_shares
+    }
+    /// fresh random bits used in each cycle
+    fn randomness(&self, num_shares: usize) -> usize;
+    /// register stages between the inputs and the output
+    fn latency(&self) -> usize;
+    /// implementation of the AND of two inputs with `num_shares` shares each
+    fn and_network(&self, num_shares: usize) -> ShareNetwork;
+}
+
+/// Domain-oriented masking for independently shared inputs (Gross et al., 2016)
+pub struct DomIndep;
+
+impl Gadget for DomIndep {
+    fn name(&self) -> &'static str {
+        "DOM-indep"
+    }
+    fn security(&self) -> Security {
+        Security::Probing
+    }
+    fn randomness(&self, num_shares: usize) -> usize {
+        num_shares * (num_shares - 1) / 2
+    }
+    fn latency(&self) -> usize {
+        1
+    }
+    fn and_network(&self, num_shares: usize) -> ShareNetwork {
+        let mut net = ShareNetwork::default();
+        let xs = ShareNetwork::inputs(0, num_shares);
+        let ys = ShareNetwork::inputs(1, num_shares);
+        net.outputs = net.dom_indep(&xs, &ys);
+        net
+    }
+}
+
+/// Domain-oriented masking for dependent inputs: `y` is blinded by a fresh sharing `z` and
+/// recombined, so that `x * y = x * (y + z) + x * z`
+pub struct DomDep;
+
+impl Gadget for DomDep {
+    fn name(&self) -> &'static str {
+        "DOM-dep"
+    }
+    fn security(&self) -> Security {
+        Security::Probing
+    }
+    fn randomness(&self, num_shares: usize) -> usize {
+        num_shares + num_shares * (num_shares - 1) / 2
+    }
+    fn latency(&self) -> usize {
+        1
+    }
+    fn and_network(&self, num_shares: usize) -> ShareNetwork {
+        let mut net = ShareNetwork::default();
+        let xs = ShareNetwork::inputs(0, num_shares);
+        let ys = ShareNetwork::inputs(1, num_shares);
+        let zs = (0..num_shares).map(|_| net.random()).collect_vec();
+        let blinded = (0..num_shares)
+            .map(|i| {
+                let b = net.xor(ys[i], zs[i]);
+                net.reg(b)
+            })
+            .collect_vec();
+        let b = net.xor_all(blinded);
+        let xz = net.dom_indep(&xs, &zs);
+        net.outputs = (0..num_shares)
+            .map(|i| {
+                let x = net.reg(xs[i]);
+                let xb = net.and(x, b);
+                net.xor(xz[i], xb)
+            })
+            .collect();
+        net
+    }
+}
+
+/// Ishai-Sahai-Wagner multiplication (2003). Not robust against glitches.
+pub struct Isw;
+
+impl Gadget for Isw {
+    fn name(&self) -> &'static str {
+        "ISW"
+    }
+    fn security(&self) -> Security {
+        Security::Sni
+    }
+    fn randomness(&self, num_shares: usize) -> usize {
+        num_shares * (num_shares - 1) / 2
+    }
+    fn latency(&self) -> usize {
+        0
+    }
+    fn and_network(&self, num_shares: usize) -> ShareNetwork {
+        let mut net = ShareNetwork::default();
+        let n = num_shares;
+        let (xs, ys) = (ShareNetwork::inputs(0, n), ShareNetwork::inputs(1, n));
+        let mut r = vec![vec![None; n]; n];
+        for (i, j) in (0..n).tuple_combinations() {
+            let rij = net.random();
+            let xy = net.and(xs[i], ys[j]);
+            let yx = net.and(xs[j], ys[i]);
+            let t = net.xor(rij, xy);
+            r[i][j] = Some(rij);
+            r[j][i] = Some(net.xor(t, yx));
+        }
+        net.outputs = (0..n)
+            .map(|i| {
+                let p = net.and(xs[i], ys[i]);
+                let terms = std::iter::once(p).chain(r[i].iter().flatten().copied());
+                net.xor_all(terms.collect_vec())
+            })
+            .collect();
+        net
+    }
+}
+
+/// Hardware private circuit 1 (Cassiers et al., 2020): SNI refresh of `y`, then DOM-indep
+pub struct Hpc1;
+
+impl Gadget for Hpc1 {
+    fn name(&self) -> &'static str {
+        "HPC1"
+    }
+    fn security(&self) -> Security {
+        Security::Pini
+    }
+    fn randomness(&self, num_shares: usize) -> usize {
+        num_shares * (num_shares - 1)
+    }
+    fn latency(&self) -> usize {
+        2
+    }
+    fn and_network(&self, num_shares: usize) -> ShareNetwork {
+        let mut net = ShareNetwork::default();
+        let n = num_shares;
+        let (xs, ys) = (ShareNetwork::inputs(0, n), ShareNetwork::inputs(1, n));
+        let r = net.random_pairs(n);
+        let refreshed = (0..n)
+            .map(|i| {
+                let terms = std::iter::once(ys[i]).chain(r[i].iter().flatten().copied());
+                let y = net.xor_all(terms.collect_vec());
+                net.reg(y)
+            })
+            .collect_vec();
+        let delayed = xs.iter().map(|&x| net.reg(x)).collect_vec();
+        net.outputs = net.dom_indep(&delayed, &refreshed);
+        net
+    }
+}
+
+/// Hardware private circuit 2 (Cassiers et al., 2020)
+pub struct Hpc2;
+
+impl Gadget for Hpc2 {
+    fn name(&self) -> &'static str {
+        "HPC2"
+    }
+    fn security(&self) -> Security {
+        Security::Pini
+    }
+    fn randomness(&self, num_shares: usize) -> usize {
+        num_shares * (num_shares - 1) / 2
+    }
+    fn latency(&self) -> usize {
+        2
+    }
+    fn and_network(&self, num_shares: usize) -> ShareNetwork {
+        let mut net = ShareNetwork::default();
+        let n = num_shares;
+        let (xs, ys) = (ShareNetwork::inputs(0, n), ShareNetwork::inputs(1, n));
+        let r = net.random_pairs(n);
+        net.outputs = (0..n)
+            .map(|i| {
+                let p = net.and(xs[i], ys[i]);
+                let mut terms = vec![net.reg(p)];
+                let x = net.reg(xs[i]);
+                let not_x = net.not(xs[i]);
+                for j in (0..n).filter(|&j| j != i) {
+                    let rij = r[i][j].unwrap();
+                    // x_i * y_j + r_ij = !x_i * r_ij + x_i * (y_j + r_ij)
+                    let u = net.and(not_x, rij);
+                    terms.push(net.reg(u));
+                    let v = net.xor(ys[j], rij);
+                    let v = net.reg(v);
+                    terms.push(net.and(x, v));
+                }
+                let z = net.xor_all(terms);
+                net.reg(z)
+            })
+            .collect();
+        net
+    }
+}
+
+/// Hardware private circuit 3 (Knichel and Moradi, 2022): HPC2 with a latency of one cycle,
+/// using twice the randomness
+pub struct Hpc3;
+
+impl Gadget for Hpc3 {
+    fn name(&self) -> &'static str {
+        "HPC3"
+    }
+    fn security(&self) -> Security {
+        Security::Pini
+    }
+    fn randomness(&self, num_shares: usize) -> usize {
+        num_shares * (num_shares - 1)
+    }
+    fn latency(&self) -> usize {
+        1
+    }
+    fn and_network(&self, num_shares: usize) -> ShareNetwork {
+        let mut net = ShareNetwork::default();
+        let n = num_shares;
+        let (xs, ys) = (ShareNetwork::inputs(0, n), ShareNetwork::inputs(1, n));
+        let r = net.random_pairs(n);
+        let r_prime = net.random_pairs(n);
+        net.outputs = (0..n)
+            .map(|i| {
+                let p = net.and(xs[i], ys[i]);
+                let mut terms = vec![net.reg(p)];
+                let not_x = net.not(xs[i]);
+                for j in (0..n).filter(|&j| j != i) {
+                    let (rij, rpij) = (r[i][j].unwrap(), r_prime[i][j].unwrap());
+                    let v = net.xor(ys[j], rij);
+                    let xv = net.and(xs[i], v);
+                    let t = net.xor(xv, rpij);
+                    terms.push(net.reg(t));
+                    let u = net.and(not_x, rij);
+                    let t = net.xor(u, rpij);
+                    terms.push(net.reg(t));
+                }
+                net.xor_all(terms)
+            })
+            .collect();
+        net
+    }
+}
+
+/// Threshold implementation (Nikova et al., 2006) with `2 * order + 1` shares: every product
+/// `x_i * y_j` goes to the component function of the share pair `{i, j}`, so any `order`
+/// components miss at least one input share (non-completeness). The components are re-masked to
+/// keep the sharing uniform, registered and compressed to the output shares.
+pub struct Ti;
+
+impl Gadget for Ti {
+    fn name(&self) -> &'static str {
+        "TI"
+    }
+    fn security(&self) -> Security {
+        Security::Probing
+    }
+    fn num_shares(&self, order: usize) -> usize {
+        2 * order + 1
+    }
+    fn randomness(&self, num_shares: usize) -> usize {
+        (num_shares * (num_shares - 1) / 2).saturating_sub(1)
+    }
+    fn latency(&self) -> usize {
+        1
+    }
+    fn and_network(&self, num_shares: usize) -> ShareNetwork {
+        let mut net = ShareNetwork::default();
+        let n = num_shares;
+        let (xs, ys) = (ShareNetwork::inputs(0, n), ShareNetwork::inputs(1, n));
+        let pairs = (0..n).tuple_combinations::<(_, _)>().collect_vec();
+        let mut components = pairs
+            .iter()
+            .map(|&(i, j)| {
+                let xy = net.and(xs[i], ys[j]);
+                let yx = net.and(xs[j], ys[i]);
+                net.xor(xy, yx)
+            })
+            .collect_vec();
+        // x_i * y_i goes to the pair {i, i + 1}
+        for i in 0..n {
+            let pair = (i.min((i + 1) % n), i.max((i + 1) % n));
+            if let Some(k) = pairs.iter().position(|&p| p == pair) {
+                let p = net.and(xs[i], ys[i]);
+                components[k] = net.xor(components[k], p);
+            }
+        }
+        // re-masking with random bits that sum to zero
+        let m = components.len();
+        if m > 1 {
+            let random = (1..m).map(|_| net.random()).collect_vec();
+            for (k, &r) in random.iter().enumerate() {
+                components[k] = net.xor(components[k], r);
+            }
+            let sum = net.xor_all(random);
+            components[m - 1] = net.xor(components[m - 1], sum);
+        }
+        let registered = components.into_iter().map(|c| net.reg(c)).collect_vec();
+        net.outputs = (0..n)
+            .map(|i| net.xor_all(registered.iter().skip(i).step_by(n).copied()))
+            .collect();
+        net
+    }
+}
+
+/// Selectable gadget scheme
+#[derive(
+    Clone,
+    Copy,
+    Debug,
+    Default,
+    PartialEq,
+    Eq,
+    Hash,
+    strum::Display,
+    strum::EnumString,
+    strum::EnumIter,
+)]
+#[strum(serialize_all = "kebab-case", ascii_case_insensitive)]
+pub enum GadgetKind {
+    #[default]
+    DomIndep,
+    DomDep,
+    Isw,
+    Hpc1,
+    Hpc2,
+    Hpc3,
+    Ti,
+}
+
+impl GadgetKind {
+    pub fn gadget(&self) -> &'static dyn Gadget {
+        match self {
+            GadgetKind::DomIndep => &DomIndep,
+            GadgetKind::DomDep => &DomDep,
+            GadgetKind::Isw => &Isw,
+            GadgetKind::Hpc1 => &Hpc1,
+            GadgetKind::Hpc2 => &Hpc2,
+            GadgetKind::Hpc3 => &Hpc3,
+            GadgetKind::Ti => &Ti,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+    use strum::IntoEnumIterator;
+
+    use super::*;
+    use crate::logic::{Logic, LogicVec};
+
+    /// value of each operation and its register stage
+    fn evaluate(
+        net: &ShareNetwork,
+        inputs: &[Vec<LogicVec>],
+        random: &[LogicVec],
+    ) -> (Vec<LogicVec>, Vec<usize>) {
+        let mut values: Vec<LogicVec> = Vec::new();
+        let mut stages: Vec<usize> = Vec::new();
+        for op in net.ops.iter() {
+            let value = |w: &Wire| match *w {
+                Wire::Input { input, share } => (inputs[input][share].clone(), Some(0)),
+                Wire::Random(k) => (random[k].clone(), None),
+                Wire::Op(i) => (values[i].clone(), Some(stages[i])),
+            };
+            let (v, stage) = match op {
+                Op::And(a, b) | Op::Xor(a, b) => {
+                    let ((a, sa), (b, sb)) = (value(a), value(b));
+                    // operands are in the same register stage, except for fresh randomness
+                    if let (Some(sa), Some(sb)) = (sa, sb) {
+                        assert_eq!(sa, sb, "{:?}", op);
+                    }
+                    let v = if matches!(op, Op::And(..)) {
+                        a.and(&b)
+                    } else {
+                        a.xor(&b)
+                    };
+                    (v, sa.or(sb))
+                }
+                Op::Not(a) => {
+                    let (a, s) = value(a);
+                    (a.not(), s)
+                }
+                Op::Register(a) => {
+                    let (a, s) = value(a);
+                    (a, s.map(|s| s + 1))
+                }
+            };
+            values.push(v);
+            stages.push(stage.unwrap_or(0));
+        }
+        (values, stages)
+    }
+
+    fn random_vec(rng: &mut StdRng, width: usize) -> LogicVec {
+        (0..width).map(|_| Logic::from(rng.gen::<bool>())).collect()
+    }
+
+    #[test]
+    fn gadgets_compute_and() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let width = 256;
+        for kind in GadgetKind::iter() {
+            let gadget = kind.gadget();
+            for order in 1..=3 {
+                let num_shares = gadget.num_shares(order);
+                let net = gadget.and_network(num_shares);
+                assert_eq!(net.num_random, gadget.randomness(num_shares), "{}", kind);
+                assert_eq!(net.outputs.len(), gadget.output_shares(num_shares));
+
+                let inputs = (0..2)
+                    .map(|_| {
+                        (0..gadget.input_shares(num_shares))
+                            .map(|_| random_vec(&mut rng, width))
+                            .collect_vec()
+                    })
+                    .collect_vec();
+                let random = (0..net.num_random)
+                    .map(|_| random_vec(&mut rng, width))
+                    .collect_vec();
+                let (values, stages) = evaluate(&net, &inputs, &random);
+                let recombine = |shares: &[LogicVec]| {
+                    shares[1..]
+                        .iter()
+                        .fold(shares[0].clone(), |acc, s| acc.xor(s))
+                };
+                let output = net
+                    .outputs
+                    .iter()
+                    .map(|w| match w {
+                        Wire::Op(i) => {
+                            assert_eq!(stages[*i], gadget.latency(), "{}", kind);
+                            values[*i].clone()
+                        }
+                        _ => panic!("output is not computed"),
+                    })
+                    .collect_vec();
+                assert_eq!(
+                    recombine(&output),
+                    recombine(&inputs[0]).and(&recombine(&inputs[1])),
+                    "{} with {} shares",
+                    kind,
+                    num_shares
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn ti_non_complete() {
+        for order in 1..=2 {
+            let num_shares = Ti.num_shares(order);
+            let net = Ti.and_network(num_shares);
+            // input shares each operation depends on
+            let mut deps: Vec<Vec<usize>> = Vec::new();
+            for op in net.ops.iter() {
+                let wire_deps = |w: &Wire| match *w {
+                    Wire::Input { share, .. } => vec![share],
+                    Wire::Random(_) => vec![],
+                    Wire::Op(i) => deps[i].clone(),
+                };
+                let d = match op {
+                    Op::And(a, b) | Op::Xor(a, b) => [wire_deps(a), wire_deps(b)].concat(),
+                    Op::Not(a) | Op::Register(a) => wire_deps(a),
+                };
+                deps.push(d.into_iter().sorted().dedup().collect());
+            }
+            let components = net
+                .ops
+                .iter()
+                .enumerate()
+                .filter(|(_, op)| matches!(op, Op::Register(_)))
+                .map(|(i, _)| &deps[i])
+                .collect_vec();
+            for probed in components.iter().combinations(order) {
+                let shares = probed.into_iter().flat_map(|d| d.iter()).unique().count();
+                assert!(shares < num_shares, "order {}", order);
+            }
+        }
+    }
+}
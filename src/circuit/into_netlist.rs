@@ -1,13 +1,105 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 
-use crate::netlist::json_netlist::{AttributeVal, Module, Netlist, Port, PortDirection};
+use petgraph::visit::EdgeRef;
+use petgraph::Direction;
 
-use super::{Circuit, Error};
+use crate::netlist::json_netlist::{
+    AttributeVal, BitVal, Cell, ConstBit, Module, Netlist, Netname, Port, PortDirection, SignalId,
+};
+
+use super::{Circuit, Error, Label, NodeIndex, NodePortId, NodeType};
+
+/// Allocates fresh signal IDs. Yosys reserves 0 and 1 for the constants.
+struct SignalAllocator {
+    next: SignalId,
+}
+
+impl SignalAllocator {
+    fn new() -> Self {
+        Self { next: 2 }
+    }
+    fn fresh(&mut self) -> SignalId {
+        let sig = self.next;
+        self.next += 1;
+        sig
+    }
+}
+
+/// split `name[idx]` into `("name", Some(idx))`
+fn split_bit_name(name: &str) -> (&str, Option<usize>) {
+    if let Some(l) = name.rfind('[') {
+        if name.ends_with(']') {
+            if let Ok(idx) = name[l + 1..name.len() - 1].parse::<usize>() {
+                return (&name[..l], Some(idx));
+            }
+        }
+    }
+    (name, None)
+}
+
+fn masq_attribute(circuit: &Circuit, nx: NodeIndex) -> Option<String> {
+    let node = &circuit.graph[nx];
+    match node.node_type {
+        NodeType::Control => Some("constant".to_owned()),
+        NodeType::Random => Some("random".to_owned()),
+        NodeType::Clock => Some("clock".to_owned()),
+        NodeType::Reset => Some("reset".to_owned()),
+        NodeType::Input => match circuit.input_share(nx) {
+            Some((share, label)) => Some(share_attribute(share, label)),
+            None => {
+                let label = circuit.input_label(nx);
+                (!label.is_public()).then(|| label.to_string())
+            }
+        },
+        _ => None,
+    }
+}
+
+/// MASQ attribute of an input delivering share `share` of a secret, e.g. `share:1:key`
+fn share_attribute(share: u8, label: &Label) -> String {
+    match label.to_string().split_once(':') {
+        Some((_, secrets)) => format!("share:{}:{}", share, secrets),
+        None => format!("share:{}", share),
+    }
+}
+
+fn blackbox_module(inputs: &[String], outputs: &[String]) -> Module {
+    let mut module = Module::default();
+    module
+        .attributes
+        .insert("blackbox".to_string(), AttributeVal::Number(1));
+    let mut signals = SignalAllocator::new();
+    let ports = inputs
+        .iter()
+        .map(|name| (name, PortDirection::Input))
+        .chain(outputs.iter().map(|name| (name, PortDirection::Output)));
+    for (name, direction) in ports {
+        let bits = vec![BitVal::Signal(signals.fresh())];
+        module.netnames.insert(
+            name.clone(),
+            Netname {
+                hide_name: false,
+                bits: bits.clone(),
+                attributes: HashMap::new(),
+            },
+        );
+        module.ports.insert(
+            name.clone(),
+            Port {
+                direction,
+                bits,
+                offset: 0,
+            },
+        );
+    }
+    module
+}
 
 impl TryFrom<&Circuit> for Netlist {
     type Error = Error;
 
     fn try_from(circuit: &Circuit) -> Result<Self, Self::Error> {
+        let graph = &circuit.graph;
         let mut modules = HashMap::new();
 
         let mut top = Module::default();
@@ -15,19 +107,173 @@ impl TryFrom<&Circuit> for Netlist {
         top.attributes
             .insert("top".to_string(), AttributeVal::Number(1));
 
-        for nx in circuit.inputs.iter() {
-            let node = &circuit.graph[*nx];
-            let port_name = node.name.as_ref().map(|s| s.to_string()).unwrap();
+        let mut signals = SignalAllocator::new();
+        // (driver node, driver output port) -> signal
+        let mut node_signals = HashMap::<(NodeIndex, NodePortId), SignalId>::new();
+
+        let mut node_signal = |nx: NodeIndex, port: NodePortId| -> BitVal {
+            match graph[nx].node_type {
+                NodeType::Constant(v) => {
+                    BitVal::Constant(if v { ConstBit::_1 } else { ConstBit::_0 })
+                }
+                _ => BitVal::Signal(
+                    *node_signals
+                        .entry((nx, port))
+                        .or_insert_with(|| signals.fresh()),
+                ),
+            }
+        };
+
+        // port name -> (direction, index -> (bit, node))
+        let mut ports =
+            BTreeMap::<String, (PortDirection, BTreeMap<usize, (BitVal, NodeIndex)>)>::new();
+
+        for nx in graph.node_indices() {
+            let node = &graph[nx];
+            let direction = match node.node_type {
+                NodeType::Input
+                | NodeType::Control
+                | NodeType::Random
+                | NodeType::Clock
+                | NodeType::Reset => {
+                    PortDirection::Input
+                }
+                NodeType::Output => PortDirection::Output,
+                _ => continue,
+            };
+            let name = node.name.clone().unwrap_or_else(|| match direction {
+                PortDirection::Output => format!("out_{}", nx.index()),
+                _ => format!("in_{}", nx.index()),
+            });
+            let (port_name, idx) = split_bit_name(&name);
+            let bit = match direction {
+                PortDirection::Output => graph
+                    .edges_directed(nx, Direction::Incoming)
+                    .next()
+                    .map(|e| node_signal(e.source(), e.weight().0))
+                    .unwrap_or(BitVal::Constant(ConstBit::X)),
+                _ => node_signal(nx, 0),
+            };
+            let (dir, bits) = ports
+                .entry(port_name.to_owned())
+                .or_insert_with(|| (direction, BTreeMap::new()));
+            if *dir != direction {
+                return Err(Error::SimpleError(simple_error::SimpleError::new(format!(
+                    "port {} has conflicting directions",
+                    port_name
+                ))));
+            }
+            bits.insert(idx.unwrap_or(0), (bit, nx));
+        }
+
+        let mut cell_ports = BTreeMap::<String, (Vec<String>, Vec<String>)>::new();
+
+        for nx in graph.node_indices() {
+            let node = &graph[nx];
+            let cell_type = match circuit.cell_type_name(&node.node_type) {
+                Some(cell_type) => cell_type,
+                None => continue,
+            };
+            let in_names = circuit.input_port_names(&nx);
+            let out_names = circuit.output_port_names(&nx);
+
+            let mut connections = HashMap::new();
+            let mut port_directions = HashMap::new();
+
+            for e in graph.edges_directed(nx, Direction::Incoming) {
+                let (src_port, dst_port) = *e.weight();
+                let port_name = in_names[dst_port as usize].clone();
+                connections.insert(port_name.clone(), vec![node_signal(e.source(), src_port)]);
+                port_directions.insert(port_name, PortDirection::Input);
+            }
+            for (port_name, out_port) in out_names.iter().zip(0..) {
+                connections.insert(port_name.clone(), vec![node_signal(nx, out_port)]);
+                port_directions.insert(port_name.clone(), PortDirection::Output);
+            }
+
+            cell_ports
+                .entry(cell_type.clone())
+                .or_insert_with(|| (in_names.clone(), out_names.clone()));
+
+            let mut attributes = HashMap::new();
+            if circuit.declassified.contains(&nx) {
+                attributes.insert(
+                    "MASQ".to_string(),
+                    AttributeVal::String("declassify".to_string()),
+                );
+            } else if let Some(label) = circuit
+                .classified
+                .get(&nx)
+                .filter(|_| !circuit.preshared.contains_key(&nx))
+            {
+                attributes.insert("MASQ".to_string(), AttributeVal::String(label.to_string()));
+            }
+            let (cell_name, hide_name) = match &node.name {
+                Some(name) if !top.cells.contains_key(name) => (name.clone(), false),
+                _ => (format!("$masq${}", nx.index()), true),
+            };
+            top.cells.insert(
+                cell_name,
+                Cell {
+                    hide_name,
+                    cell_type,
+                    parameters: HashMap::new(),
+                    attributes,
+                    port_directions,
+                    connections,
+                },
+            );
+        }
+
+        for (port_name, (direction, bits)) in ports {
+            let width = bits.keys().max().map_or(0, |m| m + 1);
+            let mut port_bits = Vec::with_capacity(width);
+            let mut attributes = HashMap::new();
+            for idx in 0..width {
+                match bits.get(&idx) {
+                    Some((bit, nx)) => {
+                        port_bits.push(*bit);
+                        if let Some(masq) = masq_attribute(circuit, *nx) {
+                            attributes.insert("MASQ".to_string(), AttributeVal::String(masq));
+                        }
+                    }
+                    None if direction == PortDirection::Input => {
+                        port_bits.push(BitVal::Signal(signals.fresh()))
+                    }
+                    None => port_bits.push(BitVal::Constant(ConstBit::X)),
+                }
+            }
+            top.netnames.insert(
+                port_name.clone(),
+                Netname {
+                    hide_name: false,
+                    bits: port_bits.clone(),
+                    attributes,
+                },
+            );
             top.ports.insert(
                 port_name,
                 Port {
-                    direction: PortDirection::Input,
-                    bits: Vec::new(),
+                    direction,
+                    bits: port_bits,
                     offset: Default::default(),
                 },
             );
         }
 
+        for (bb_name, bb) in circuit.blackboxes.iter() {
+            let mut inputs = bb.inputs.clone();
+            inputs.sort();
+            let mut outputs = bb.outputs.clone();
+            outputs.sort();
+            modules.insert(bb_name.clone(), blackbox_module(&inputs, &outputs));
+        }
+        for (cell_type, (inputs, outputs)) in cell_ports {
+            modules
+                .entry(cell_type)
+                .or_insert_with(|| blackbox_module(&inputs, &outputs));
+        }
+
         modules.insert(circuit.name.clone(), top);
 
         Ok(Netlist {
@@ -36,3 +282,65 @@ impl TryFrom<&Circuit> for Netlist {
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::circuit::test_utils::simple_1;
+    use crate::circuit::{Masking, NetlistAndLibrary};
+
+    fn round_trip(circuit: &Circuit) -> Circuit {
+        let netlist = Netlist::try_from(circuit).unwrap();
+        let json = netlist.to_string().unwrap();
+        let netlist = Netlist::from_slice(json.as_bytes()).unwrap();
+        Circuit::try_from(&NetlistAndLibrary::new(netlist)).unwrap()
+    }
+
+    #[test]
+    fn round_trip_unmasked() {
+        let circuit = simple_1();
+        let reloaded = round_trip(&circuit);
+        assert_eq!(reloaded.name, circuit.name);
+        assert_eq!(reloaded.stats(), circuit.stats());
+    }
+
+    #[test]
+    fn round_trip_masked() {
+        let mut circuit = simple_1();
+        circuit.mask(1).unwrap();
+        let netlist = Netlist::try_from(&circuit).unwrap();
+        let top = &netlist.modules[&circuit.name];
+        assert_eq!(top.ports["in_data_s0"].bits.len(), 2);
+        assert_eq!(top.ports["in_data_s1"].bits.len(), 2);
+        assert_eq!(top.ports["out_data_s1"].direction, PortDirection::Output);
+        // public control inputs keep a single share
+        assert!(top.ports.contains_key("in_valid"));
+        assert!(!top.ports.contains_key("in_valid_s0"));
+        assert_eq!(
+            top.netnames["in_valid"].attributes["MASQ"],
+            AttributeVal::String("constant".to_owned())
+        );
+        // fresh randomness of all gadgets on a single bus
+        let num_random = circuit.random_inputs().len();
+        assert!(num_random > 0);
+        assert_eq!(top.ports["rand"].bits.len(), num_random);
+        assert_eq!(
+            top.netnames["rand"].attributes["MASQ"],
+            AttributeVal::String("random".to_owned())
+        );
+        assert!(top
+            .ports
+            .values()
+            .flat_map(|p| p.bits.iter())
+            .all(|b| *b != BitVal::Constant(ConstBit::X)));
+
+        let reloaded = round_trip(&circuit);
+        assert_eq!(reloaded.random_inputs().len(), num_random);
+        let (stats, reloaded_stats) = (circuit.stats(), reloaded.stats());
+        assert_eq!(reloaded_stats.num_nodes, stats.num_nodes);
+        assert_eq!(reloaded_stats.num_edges, stats.num_edges);
+        assert_eq!(reloaded_stats.num_inputs, stats.num_inputs);
+        assert_eq!(reloaded_stats.num_outputs, stats.num_outputs);
+        assert_eq!(reloaded_stats.num_registers, stats.num_registers);
+    }
+}
@@ -0,0 +1,339 @@
+use std::collections::{HashMap, HashSet};
+
+use itertools::Itertools;
+use petgraph::algo::toposort;
+use petgraph::visit::{EdgeFiltered, EdgeRef};
+use petgraph::Direction;
+use simple_error::SimpleError;
+
+use super::node::{Node, NodePortId, NodeType};
+use super::{Circuit, Error, NodeIndex};
+
+/// output port of a node
+type Signal = (NodeIndex, NodePortId);
+
+/// Registers delaying a signal by 1, 2, ... cycles, shared by all its consumers
+#[derive(Debug, Default)]
+struct DelayChains(HashMap<Signal, Vec<NodeIndex>>);
+
+impl Circuit {
+    /// Cycles by which the value of every node lags behind the unmasked circuit, due to the
+    /// gadgets on its share paths. A register has the latency of its data input. Registers on
+    /// loops through gadgets can not be aligned and start again from 0, see
+    /// [`Circuit::unaligned_registers`].
+    /// Only meaningful before the gadgets are expanded.
+    pub fn latencies(&self) -> Result<HashMap<NodeIndex, usize>, Error> {
+        self.register_latencies().map(|(latencies, _)| latencies)
+    }
+
+    /// latencies of all nodes, and the registers on loops through gadgets restarted at 0
+    fn register_latencies(&self) -> Result<(HashMap<NodeIndex, usize>, HashSet<NodeIndex>), Error> {
+        let graph = &self.graph;
+        let combinational =
+            EdgeFiltered::from_fn(graph, |e| graph[e.target()].node_type != NodeType::Register);
+        let order = toposort(&combinational, None).map_err(|cycle| {
+            SimpleError::new(format!(
+                "combinational loop through node {:?}",
+                graph[cycle.node_id()]
+            ))
+        })?;
+        // no acyclic path can be slower than all gadgets in a row
+        let max_latency: usize = graph
+            .node_weights()
+            .map(|node| self.gadget_latency(&node.node_type))
+            .sum();
+
+        let mut latencies: HashMap<NodeIndex, usize> = HashMap::new();
+        let mut restarted = HashSet::new();
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for &nx in order.iter() {
+                let is_register = graph[nx].node_type == NodeType::Register;
+                let mut latency = graph
+                    .neighbors_directed(nx, Direction::Incoming)
+                    .map(|sx| latencies.get(&sx).copied().unwrap_or(0))
+                    .max()
+                    .unwrap_or(0)
+                    + self.gadget_latency(&graph[nx].node_type);
+                if is_register && (latency > max_latency || restarted.contains(&nx)) {
+                    restarted.insert(nx);
+                    latency = 0;
+                }
+                let old = latencies.insert(nx, latency);
+                // registers are visited before their data inputs, so loops need another pass
+                changed |= is_register && old != Some(latency);
+            }
+        }
+        Ok((latencies, restarted))
+    }
+
+    /// Names of the registers on loops through gadgets, which `balance_latency` could not align:
+    /// their state mixes values of different cycles, so the masked circuit is not
+    /// cycle-equivalent to the original one.
+    pub fn unaligned_registers(&self) -> Vec<String> {
+        self.unaligned_registers
+            .iter()
+            .map(|&rx| {
+                let node = &self.graph[rx];
+                node.name
+                    .clone()
+                    .unwrap_or_else(|| format!("{}_{}", node.node_type, rx.index()))
+            })
+            .sorted()
+            .collect()
+    }
+
+    /// Align the masked circuit in time: insert registers on the inputs of every gate and gadget
+    /// that arrive earlier than the others, both shares and public control signals such as
+    /// register enables and MUX selects. The secure outputs are delayed to the slowest one,
+    /// which gives the latency of the circuit, and so are the public outputs which only depend on
+    /// registers, such as valid signals, or on declassified values. Delayed values are shared by
+    /// all their consumers. Registers on loops through gadgets are left unaligned and reported by
+    /// [`Circuit::unaligned_registers`].
+    /// Returns the number of inserted registers.
+    pub fn balance_latency(&mut self) -> Result<usize, Error> {
+        let (latencies, restarted) = self.register_latencies()?;
+        self.unaligned_registers = restarted;
+        self.delayed_outputs = self
+            .outputs
+            .iter()
+            .copied()
+            .filter(|&ox| self.graph[ox].secure || latencies[&ox] > 0 || !self.has_input_path(ox))
+            .collect();
+        self.latency = self
+            .delayed_outputs
+            .iter()
+            .map(|ox| latencies[ox])
+            .max()
+            .unwrap_or(0);
+
+        let mut chains = DelayChains::default();
+        let nodes = self.graph.node_indices().collect_vec();
+        for nx in nodes {
+            let node = &self.graph[nx];
+            let target = match node.node_type {
+                NodeType::Output if self.delayed_outputs.contains(&nx) => self.latency,
+                NodeType::Gate(..) | NodeType::Gadget { .. } | NodeType::Blackbox(_) => {
+                    latencies[&nx] - self.gadget_latency(&node.node_type)
+                }
+                _ => continue,
+            };
+            let late_inputs = self
+                .graph
+                .edges_directed(nx, Direction::Incoming)
+                .filter(|e| {
+                    !matches!(
+                        self.graph[e.source()].node_type,
+                        NodeType::Random | NodeType::Clock | NodeType::Constant(_)
+                    ) && latencies[&e.source()] < target
+                })
+                .map(|e| (e.id(), e.source(), *e.weight()))
+                .collect_vec();
+            for (ex, sx, (src_port, dst_port)) in late_inputs {
+                let cycles = target - latencies[&sx];
+                let (dx, dp) = self.delayed(&mut chains, (sx, src_port), cycles);
+                self.graph.remove_edge(ex);
+                self.connect(dx, dp, nx, dst_port);
+            }
+        }
+        Ok(chains.0.values().map(Vec::len).sum())
+    }
+
+    /// Cycles by which an output of a masked circuit lags behind the original circuit
+    pub fn output_latency(&self, ox: NodeIndex) -> usize {
+        if self.delayed_outputs.contains(&ox) {
+            self.latency
+        } else {
+            0
+        }
+    }
+
+    /// whether a combinational path leads from an input to `nx`, e.g. for a ready signal
+    fn has_input_path(&self, nx: NodeIndex) -> bool {
+        let mut visited = HashSet::new();
+        let mut stack = vec![nx];
+        while let Some(nx) = stack.pop() {
+            if !visited.insert(nx) {
+                continue;
+            }
+            match self.graph[nx].node_type {
+                NodeType::Input | NodeType::Control | NodeType::Reset => return true,
+                NodeType::Register => {}
+                _ => stack.extend(self.graph.neighbors_directed(nx, Direction::Incoming)),
+            }
+        }
+        false
+    }
+
+    /// `signal` delayed by `cycles` registers, extending its delay chain as needed
+    fn delayed(&mut self, chains: &mut DelayChains, signal: Signal, cycles: usize) -> Signal {
+        let mut chain = chains.0.remove(&signal).unwrap_or_default();
+        while chain.len() < cycles {
+            let (dx, dp) = chain.last().map_or(signal, |&rx| (rx, 0));
+            let (cx, cp) = self.gadget_clock();
+            let rx = self.add_node(Node {
+                secure: self.graph[signal.0].secure,
+                node_type: NodeType::Register,
+                name: None,
+            });
+            self.connect(cx, cp, rx, 0);
+            self.connect(dx, dp, rx, 1);
+            chain.push(rx);
+        }
+        let delayed = match cycles {
+            0 => signal,
+            _ => (chain[cycles - 1], 0),
+        };
+        chains.0.insert(signal, chain);
+        delayed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    use super::*;
+    use crate::circuit::node::GateType;
+    use crate::circuit::test_utils::simple_1;
+    use crate::circuit::{GadgetKind, Masking, Simulator};
+    use crate::logic::{Logic, LogicVec};
+
+    /// `y = (a & b) ^ c`, with secure inputs
+    fn and_xor() -> Circuit {
+        let mut circuit = Circuit::default();
+        let [a, b, c] =
+            ["a", "b", "c"].map(|name| circuit.add_test_node(true, NodeType::Input, name));
+        let and = circuit.add_test_node(false, NodeType::Gate(GateType::And(2), false), "");
+        let xor = circuit.add_test_node(false, NodeType::Gate(GateType::Xor(2), false), "");
+        let y = circuit.add_test_node(false, NodeType::Output, "y");
+        circuit.connect(a, 0, and, 0);
+        circuit.connect(b, 0, and, 1);
+        circuit.connect(and, 0, xor, 0);
+        circuit.connect(c, 0, xor, 1);
+        circuit.connect(xor, 0, y, 0);
+        circuit
+    }
+
+    #[test]
+    fn balanced_and_xor() -> Result<(), Error> {
+        let mut masked = and_xor();
+        masked.mask_with(1, GadgetKind::DomIndep)?;
+        // masking balances the latency, with a register for both shares of `c`
+        assert_eq!(masked.latency(), 1);
+        assert_eq!(masked.registers.len(), 2);
+        masked.expand_gadgets();
+
+        let width = 64;
+        let mut rng = StdRng::seed_from_u64(0);
+        let mut random_vec =
+            || -> LogicVec { (0..width).map(|_| Logic::from(rng.gen::<bool>())).collect() };
+        let mut sim = Simulator::new(&masked, width)?;
+        let mut expected = None;
+        for _ in 0..4 {
+            let mut stimulus = HashMap::new();
+            let mut y = None;
+            for name in ["a", "b", "c"] {
+                let (s0, s1) = (random_vec(), random_vec());
+                let value = s0.xor(&s1);
+                y = Some(match (name, y) {
+                    ("a", _) => value,
+                    ("b", Some(a)) => value.and(&a),
+                    (_, Some(ab)) => value.xor(&ab),
+                    _ => unreachable!(),
+                });
+                stimulus.insert(format!("{}_s0", name), s0);
+                stimulus.insert(format!("{}_s1", name), s1);
+            }
+            for rx in masked.random_inputs() {
+                let name = masked.graph[rx].name.clone().unwrap();
+                stimulus.insert(name, random_vec());
+            }
+            let (outputs, _) = sim.step(&stimulus)?;
+            // the result of the inputs of the previous cycle
+            if let Some(expected) = expected {
+                assert_eq!(outputs["y_s0"].xor(&outputs["y_s1"]), expected);
+            }
+            expected = y;
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn decomposed_and() -> Result<(), Error> {
+        let mut circuit = Circuit::default();
+        let and = circuit.add_test_node(false, NodeType::Gate(GateType::And(5), false), "");
+        for port in 0..5 {
+            let input = circuit.add_test_node(true, NodeType::Input, &format!("a{}", port));
+            circuit.connect(input, 0, and, port);
+        }
+        let y = circuit.add_test_node(false, NodeType::Output, "y");
+        circuit.connect(and, 0, y, 0);
+
+        let mut masked = circuit.clone();
+        masked.mask_with(1, GadgetKind::DomIndep)?;
+        let gadgets = masked
+            .graph
+            .node_weights()
+            .filter_map(|node| node.node_type.num_gadget_operands())
+            .collect_vec();
+        // a tree of 2-input gadgets with a depth of ceil(log2(5))
+        assert_eq!(gadgets, [2; 4]);
+        assert_eq!(masked.latency(), 3);
+
+        let mut rng = StdRng::seed_from_u64(0);
+        assert_eq!(circuit.check_equivalence(&masked, 8, 64, &mut rng)?, None);
+        masked.expand_gadgets();
+        assert_eq!(circuit.check_equivalence(&masked, 8, 64, &mut rng)?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn gadget_feedback_loop() -> Result<(), Error> {
+        // `r <= r & a`, the state of an iterated cipher
+        let mut circuit = Circuit::default();
+        let clk = circuit.add_test_node(false, NodeType::Clock, "clk");
+        let a = circuit.add_test_node(true, NodeType::Input, "a");
+        let r = circuit.add_test_node(false, NodeType::Register, "r");
+        let and = circuit.add_test_node(false, NodeType::Gate(GateType::And(2), false), "and");
+        let y = circuit.add_test_node(false, NodeType::Output, "y");
+        circuit.connect(clk, 0, r, 0);
+        circuit.connect(and, 0, r, 1);
+        circuit.connect(r, 0, and, 0);
+        circuit.connect(a, 0, and, 1);
+        circuit.connect(r, 0, y, 0);
+
+        let mut masked = circuit.clone();
+        masked.mask_with(1, GadgetKind::DomIndep)?;
+        // both shares of the register are reported
+        assert_eq!(masked.unaligned_registers(), ["r_s0", "r_s1"]);
+        assert!(circuit.unaligned_registers().is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn delayed_handshake() -> Result<(), Error> {
+        let circuit = simple_1();
+        let mut masked = circuit.clone();
+        masked.mask_with(1, GadgetKind::Hpc1)?;
+        assert_eq!(masked.latency(), 2);
+        let output = |name: &str| {
+            *masked
+                .outputs
+                .iter()
+                .find(|&&ox| masked.graph[ox].name.as_deref() == Some(name))
+                .unwrap()
+        };
+        // the valid signal comes with the data, the ready signal answers the inputs
+        assert_eq!(masked.output_latency(output("out_valid")), 2);
+        assert_eq!(masked.output_latency(output("in_ready")), 0);
+
+        let mut rng = StdRng::seed_from_u64(0);
+        assert_eq!(circuit.check_equivalence(&masked, 16, 64, &mut rng)?, None);
+        masked.expand_gadgets();
+        assert_eq!(circuit.check_equivalence(&masked, 16, 64, &mut rng)?, None);
+        Ok(())
+    }
+}
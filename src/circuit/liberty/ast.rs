@@ -0,0 +1,60 @@
+pub type Ident = String;
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Value {
+    Ident(Ident),
+    Number(String),
+    /// string contents and their offset in the source
+    String(String, usize),
+}
+
+impl Value {
+    pub fn as_str(&self) -> &str {
+        match self {
+            Value::Ident(s) | Value::Number(s) | Value::String(s, _) => s,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Item {
+    Group(Group),
+    /// `name : value ;`
+    Attribute(Ident, Value),
+    /// `name ( values ) ;`
+    ComplexAttribute(Ident, Vec<Value>),
+}
+
+/// `kind ( args ) { items }`, e.g. `cell (NAND2) { ... }`
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Group {
+    pub kind: Ident,
+    pub args: Vec<Value>,
+    pub items: Vec<Item>,
+}
+
+impl Group {
+    pub fn groups<'a>(&'a self, kind: &'a str) -> impl Iterator<Item = &'a Group> + 'a {
+        self.items.iter().filter_map(move |item| match item {
+            Item::Group(g) if g.kind == kind => Some(g),
+            _ => None,
+        })
+    }
+    pub fn attribute(&self, name: &str) -> Option<&Value> {
+        self.items.iter().find_map(|item| match item {
+            Item::Attribute(n, v) if n == name => Some(v),
+            _ => None,
+        })
+    }
+}
+
+/// Boolean function of a pin, e.g. `"(A*B)'"`
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Function {
+    Var(Ident),
+    Const(bool),
+    Not(Box<Function>),
+    And(Box<Function>, Box<Function>),
+    Or(Box<Function>, Box<Function>),
+    Xor(Box<Function>, Box<Function>),
+}
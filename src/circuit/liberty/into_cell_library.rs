@@ -0,0 +1,243 @@
+use itertools::Itertools;
+
+use super::super::cell_library::CellLibrary;
+use super::super::node::{GateType, NodeType};
+use super::ast::{Function, Group, Value};
+use super::reader::{Error, Source};
+
+/// `f` without its outermost inversions, and whether it was inverted
+fn strip_not(f: &Function) -> (&Function, bool) {
+    match f {
+        Function::Not(inner) => {
+            let (f, invert) = strip_not(inner);
+            (f, !invert)
+        }
+        _ => (f, false),
+    }
+}
+
+/// variable name and inversion of a literal, e.g. `A` or `!A`
+fn literal(f: &Function) -> Option<(&str, bool)> {
+    match strip_not(f) {
+        (Function::Var(v), invert) => Some((v, invert)),
+        _ => None,
+    }
+}
+
+/// operands of a chain of the same associative operator
+fn operands<'a>(
+    f: &'a Function,
+    op: fn(&'a Function) -> Option<(&'a Function, &'a Function)>,
+) -> Vec<&'a Function> {
+    match op(f) {
+        Some((l, r)) => {
+            let mut v = operands(l, op);
+            v.extend(operands(r, op));
+            v
+        }
+        None => vec![f],
+    }
+}
+
+fn and_operands(f: &Function) -> Vec<&Function> {
+    operands(f, |f| match f {
+        Function::And(l, r) => Some((l, r)),
+        _ => None,
+    })
+}
+
+/// input order of a multiplexer `(S & B) | (A & !S)`: select, input selected when S=0, input selected when S=1
+fn mux_inputs(terms: &[&Function]) -> Option<Vec<String>> {
+    let [t0, t1] = terms else {
+        return None;
+    };
+    let literals = |t: &Function| -> Option<[(String, bool); 2]> {
+        let ops = and_operands(t);
+        let [a, b] = ops.as_slice() else {
+            return None;
+        };
+        let (a, b) = (literal(a)?, literal(b)?);
+        Some([(a.0.to_owned(), a.1), (b.0.to_owned(), b.1)])
+    };
+    let (l0, l1) = (literals(t0)?, literals(t1)?);
+    for (s, s_inv) in l0.iter() {
+        let Some(j) = l1.iter().position(|(v, inv)| v == s && inv != s_inv) else {
+            continue;
+        };
+        // the other input of each term
+        let (x0, x0_inv) = l0.iter().find(|(v, _)| v != s)?;
+        let (x1, x1_inv) = &l1[1 - j];
+        if *x0_inv || *x1_inv || x0 == x1 || x1 == s {
+            return None;
+        }
+        // the first term is selected when `s` is 0 if `s` is inverted in it
+        return Some(if *s_inv {
+            vec![s.clone(), x0.clone(), x1.clone()]
+        } else {
+            vec![s.clone(), x1.clone(), x0.clone()]
+        });
+    }
+    None
+}
+
+/// names of the operands if they are all (non-inverted) variables
+fn vars<'a>(ops: &[&'a Function]) -> Option<Vec<&'a str>> {
+    ops.iter()
+        .map(|op| match op {
+            Function::Var(v) => Some(v.as_str()),
+            _ => None,
+        })
+        .collect()
+}
+
+/// whether each of the inputs is used exactly once
+fn uses_all_inputs<S: AsRef<str>>(vars: &[S], inputs: &[String]) -> bool {
+    let vars = vars.iter().map(|v| v.as_ref()).sorted().collect_vec();
+    let inputs = inputs.iter().map(|i| i.as_str()).sorted().collect_vec();
+    vars == inputs
+}
+
+/// gate computing `function` of all `inputs`, and the order of its inputs
+fn gate(function: &Function, inputs: &[String]) -> Option<(NodeType, Vec<String>)> {
+    let (function, mut invert) = strip_not(function);
+    let (gate_type, vars) = match function {
+        Function::Var(v) => (GateType::Buf, vec![v.as_str()]),
+        Function::And(..) => {
+            let ops = and_operands(function);
+            (GateType::And(ops.len() as u8), vars(&ops)?)
+        }
+        Function::Or(..) => {
+            let ops = operands(function, |f| match f {
+                Function::Or(l, r) => Some((l, r)),
+                _ => None,
+            });
+            if let Some(order) = mux_inputs(&ops) {
+                return uses_all_inputs(&order, inputs)
+                    .then_some((NodeType::Gate(GateType::Mux, invert), order));
+            }
+            (GateType::Or(ops.len() as u8), vars(&ops)?)
+        }
+        Function::Xor(..) => {
+            let ops = operands(function, |f| match f {
+                Function::Xor(l, r) => Some((l, r)),
+                _ => None,
+            });
+            let mut vars = Vec::new();
+            for op in ops.iter() {
+                // inverted operands invert the output
+                let (v, inv) = literal(op)?;
+                invert ^= inv;
+                vars.push(v);
+            }
+            (GateType::Xor(ops.len() as u8), vars)
+        }
+        _ => return None,
+    };
+    // the gate is symmetric, so the declared order of the pins is kept
+    uses_all_inputs(&vars, inputs).then(|| (NodeType::Gate(gate_type, invert), inputs.to_vec()))
+}
+
+/// a D flip-flop clocked on the rising edge, without set or reset, or why `ff` is not one
+fn register(
+    source: &Source,
+    ff: &Group,
+    output_function: &Function,
+    inputs: &[String],
+) -> Result<Result<(NodeType, Vec<String>), String>, Error> {
+    let iq = ff.args.first().map(Value::as_str);
+    let (Some(clocked_on), Some(next_state)) =
+        (ff.attribute("clocked_on"), ff.attribute("next_state"))
+    else {
+        return Ok(Err("flip-flop without clocked_on or next_state".to_owned()));
+    };
+    if let Some(set_reset) = ["clear", "preset"]
+        .iter()
+        .find(|a| ff.attribute(a).is_some())
+    {
+        return Ok(Err(format!("flip-flop with asynchronous {}", set_reset)));
+    }
+    let (clocked_on, next_state_function) =
+        (source.function(clocked_on)?, source.function(next_state)?);
+    match (&clocked_on, &next_state_function, output_function) {
+        (Function::Var(c), Function::Var(d), Function::Var(q))
+            if Some(q.as_str()) == iq
+                && inputs.len() == 2
+                && inputs.contains(c)
+                && inputs.contains(d)
+                && c != d =>
+        {
+            Ok(Ok((NodeType::Register, vec![c.clone(), d.clone()])))
+        }
+        (Function::Var(_), Function::Var(_), Function::Var(q)) if Some(q.as_str()) == iq => Ok(
+            Err("flip-flop with inputs other than a clock and a data pin".to_owned()),
+        ),
+        (Function::Var(_), Function::Var(_), _) => Ok(Err(format!(
+            "flip-flop whose output is not its state {}",
+            iq.unwrap_or_default()
+        ))),
+        (Function::Var(_), _, _) => Ok(Err(format!(
+            "flip-flop with next_state \"{}\", e.g. an enable or a scan input",
+            next_state.as_str()
+        ))),
+        _ => Ok(Err(
+            "flip-flop which is not clocked on the rising edge of a pin".to_owned(),
+        )),
+    }
+}
+
+fn add_cell(cell_library: &mut CellLibrary, source: &Source, cell: &Group) -> Result<(), Error> {
+    let Some(cell_name) = cell.args.first().map(Value::as_str) else {
+        return Ok(());
+    };
+    let mut inputs = Vec::new();
+    let mut outputs = Vec::new();
+    for pin in cell.groups("pin") {
+        let direction = pin.attribute("direction").map(Value::as_str);
+        for pin_name in pin.args.iter().map(Value::as_str) {
+            match direction {
+                Some("input") => inputs.push(pin_name.to_owned()),
+                Some("output") => outputs.push((pin_name.to_owned(), pin.attribute("function"))),
+                _ => (),
+            }
+        }
+    }
+    let ff = cell.groups("ff").next();
+    let is_latch = cell.groups("latch").next().is_some();
+    // combinational cells which are not gates are blackboxes, sequential cells which are not
+    // registers can not be masked
+    let gate_or_register = match (outputs.as_slice(), ff) {
+        ([(_, Some(function))], Some(ff)) => {
+            register(source, ff, &source.function(function)?, &inputs)?.map(Some)
+        }
+        (_, Some(_)) => Err("flip-flop without a single output with a function".to_owned()),
+        _ if is_latch => Err("latch".to_owned()),
+        ([(_, Some(function))], None) => Ok(gate(&source.function(function)?, &inputs)),
+        _ => Ok(None),
+    };
+    let outputs = outputs.into_iter().map(|(name, _)| name).collect();
+    match gate_or_register {
+        Ok(Some((node_type, input_order))) => {
+            cell_library.add_cell(cell_name, node_type, input_order, outputs)
+        }
+        Ok(None) => cell_library.add_cell(
+            cell_name,
+            NodeType::Blackbox(cell_name.to_owned()),
+            inputs,
+            outputs,
+        ),
+        Err(reason) => cell_library.add_unsupported_cell(cell_name, reason, inputs, outputs),
+    }
+    Ok(())
+}
+
+/// add all cells of a `library` group
+pub(super) fn add_cells(
+    cell_library: &mut CellLibrary,
+    source: &Source,
+    library: &Group,
+) -> Result<(), Error> {
+    for cell in library.groups("cell") {
+        add_cell(cell_library, source, cell)?;
+    }
+    Ok(())
+}
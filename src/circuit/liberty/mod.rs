@@ -0,0 +1,5 @@
+mod ast;
+mod into_cell_library;
+mod reader;
+
+pub use reader::Error;
@@ -0,0 +1,182 @@
+use std::path::Path;
+
+use lalrpop_util::lalrpop_mod;
+
+use crate::utils::parse_error_report;
+
+use super::super::cell_library::CellLibrary;
+use super::ast::{Function, Group, Value};
+use super::into_cell_library::add_cells;
+
+lalrpop_mod!(pub grammar, "/circuit/liberty/grammar.rs");
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error(transparent)]
+    IoError(#[from] std::io::Error),
+    /// syntax error, with a report pointing to the source
+    #[error("Error while parsing Liberty library:\n{0}")]
+    ParseError(String),
+}
+
+/// Source of a Liberty library, used to parse pin functions and to report errors
+pub(super) struct Source<'a> {
+    pub name: &'a str,
+    pub src: &'a str,
+}
+
+impl<'a> Source<'a> {
+    fn parse(&self) -> Result<Group, Error> {
+        grammar::LibraryParser::new()
+            .parse(self.src)
+            .map_err(|e| Error::ParseError(parse_error_report(self.name, self.src, e)))
+    }
+
+    /// parse the value of a `function`, `clocked_on`, or `next_state` attribute
+    pub fn function(&self, value: &Value) -> Result<Function, Error> {
+        let parser = grammar::FunctionParser::new();
+        match value {
+            Value::String(s, offset) => parser.parse(s).map_err(|e| {
+                let e = e.map_location(|l| l + offset);
+                Error::ParseError(parse_error_report(self.name, self.src, e))
+            }),
+            _ => parser
+                .parse(value.as_str())
+                .map_err(|e| Error::ParseError(parse_error_report(self.name, value.as_str(), e))),
+        }
+    }
+}
+
+impl CellLibrary {
+    /// Cell library with the cells of a Liberty library in addition to the default cells.
+    /// `name` identifies the source in error reports.
+    pub fn from_liberty_str(name: &str, src: &str) -> Result<Self, Error> {
+        let source = Source { name, src };
+        let library = source.parse()?;
+        let mut cell_library = CellLibrary::new();
+        add_cells(&mut cell_library, &source, &library)?;
+        Ok(cell_library)
+    }
+
+    /// Cell library from a Liberty (.lib) file. See [CellLibrary::from_liberty_str].
+    pub fn from_liberty_path<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        let src = std::fs::read_to_string(&path)?;
+        Self::from_liberty_str(&path.as_ref().to_string_lossy(), &src)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::super::node::GateType;
+    use super::super::super::{Circuit, NetlistAndLibrary, NodeType};
+    use super::*;
+
+    fn custom_cells() -> CellLibrary {
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/custom_cells.lib");
+        CellLibrary::from_liberty_path(path).unwrap()
+    }
+
+    #[test]
+    fn parse_functions() {
+        let parser = grammar::FunctionParser::new();
+        let var = |v: &str| Box::new(Function::Var(v.to_owned()));
+        assert_eq!(
+            parser.parse("A B' + C").unwrap(),
+            Function::Or(
+                Box::new(Function::And(var("A"), Box::new(Function::Not(var("B"))))),
+                var("C")
+            )
+        );
+        assert_eq!(
+            parser.parse("!A ^ B * C").unwrap(),
+            Function::And(
+                Box::new(Function::Xor(Box::new(Function::Not(var("A"))), var("B"))),
+                var("C")
+            )
+        );
+    }
+
+    #[test]
+    fn custom_cells_library() {
+        let lib = custom_cells();
+        let cases = [
+            ("BUF", NodeType::Gate(GateType::Buf, false)),
+            ("NOT", NodeType::Gate(GateType::Buf, true)),
+            ("NAND", NodeType::Gate(GateType::And(2), true)),
+            ("OR", NodeType::Gate(GateType::Or(2), false)),
+            ("XNOR", NodeType::Gate(GateType::Xor(2), true)),
+            ("MUX", NodeType::Gate(GateType::Mux, false)),
+            ("DFF", NodeType::Register),
+        ];
+        for (cell, node_type) in cases {
+            assert_eq!(
+                lib.node_type_from_cell_type(cell),
+                Some(node_type),
+                "{}",
+                cell
+            );
+        }
+        assert_eq!(lib.get_input_port_order("MUX").unwrap(), &["S", "A", "B"]);
+        assert_eq!(lib.get_output_port_order("MUX").unwrap(), &["Z"]);
+        assert_eq!(lib.get_input_port_order("DFF").unwrap(), &["C", "D"]);
+        assert_eq!(lib.get_input_port_order("NAND").unwrap(), &["A", "B"]);
+
+        let lib = CellLibrary::from_liberty_str(
+            "test.lib",
+            r#"library(test) {
+                cell(MX2) {
+                    pin(I0) { direction: input; }
+                    pin(I1) { direction: input; }
+                    pin(S0) { direction: input; }
+                    pin(ZN) { direction: output; function: "!((I0 & !S0) | (I1 & S0))"; }
+                }
+                cell(AOI21) {
+                    pin(A1) { direction: input; }
+                    pin(A2) { direction: input; }
+                    pin(B) { direction: input; }
+                    pin(ZN) { direction: output; function: "!((A1 & A2) | B)"; }
+                }
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(
+            lib.node_type_from_cell_type("MX2"),
+            Some(NodeType::Gate(GateType::Mux, true))
+        );
+        assert_eq!(
+            lib.get_input_port_order("MX2").unwrap(),
+            &["S0", "I0", "I1"]
+        );
+        assert_eq!(
+            lib.node_type_from_cell_type("AOI21"),
+            Some(NodeType::Blackbox("AOI21".to_owned()))
+        );
+        assert_eq!(
+            lib.port_direction("AOI21", "ZN"),
+            Some(crate::netlist::json_netlist::PortDirection::Output)
+        );
+    }
+
+    #[test]
+    fn syntax_error() {
+        let src = "library(test) {\n  cell(X) {\n    pin(Y) { direction: output; function: \"A +\"; }\n  }\n}\n";
+        match CellLibrary::from_liberty_str("test.lib", src) {
+            Err(Error::ParseError(report)) => assert!(report.contains("test.lib:3:"), "{}", report),
+            r => panic!("expected a syntax error, got {:?}", r.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn netlist_with_library() -> Result<(), crate::circuit::Error> {
+        let path = concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/tests/Xoodyak/mkPerm_netlist.v"
+        );
+        let with_library = NetlistAndLibrary::from_path_and_library(path, custom_cells())?;
+        let circuit = Circuit::try_from(&with_library)?;
+        let default = Circuit::try_from(&NetlistAndLibrary::from_path(path)?)?;
+        assert_eq!(circuit.stats().num_edges, default.stats().num_edges);
+        assert_eq!(circuit.stats().num_nodes, default.stats().num_nodes);
+        Ok(())
+    }
+}
@@ -6,28 +6,55 @@ use petgraph::{
     visit::{Dfs, EdgeRef, Reversed},
     Direction,
 };
+use simple_error::SimpleError;
 use crate::circuit::node::NodeType;
 
 use super::{
-    node::{GateType},
-    Circuit,
+    node::{GateType, NodePortId},
+    Circuit, Error, GadgetKind, NodeIndex,
 };
 
 pub trait Masking {
-    fn mask(&mut self, order: u8);
+    /// Mask with gadgets of the `gadget` scheme, without aligning the shares in time. Fails if
+    /// the pre-shared inputs of a secret are not exactly its shares at `order`.
+    fn mask_unbalanced(&mut self, order: u8, gadget: GadgetKind) -> Result<(), Error>;
+
+    /// Mask with gadgets of the `gadget` scheme and align the shares and control signals in
+    /// time, see [Circuit::balance_latency]. Returns the number of inserted registers.
+    fn mask_with(&mut self, order: u8, gadget: GadgetKind) -> Result<usize, Error>;
+
+    fn mask(&mut self, order: u8) -> Result<usize, Error> {
+        self.mask_with(order, GadgetKind::default())
+    }
 }
 
 impl Masking for Circuit {
-    fn mask(&mut self, order: u8) {
-        let num_shares = order + 1;
+    fn mask_with(&mut self, order: u8, gadget: GadgetKind) -> Result<usize, Error> {
+        self.mask_unbalanced(order, gadget)?;
+        self.balance_latency()
+    }
+
+    fn mask_unbalanced(&mut self, order: u8, gadget: GadgetKind) -> Result<(), Error> {
+        let num_shares = gadget.gadget().num_shares(order as usize) as u8;
+        self.check_preshared(num_shares)?;
+        self.gadget = gadget;
 
         self.propagate_secure();
         self.convert_secure_ors();
+        self.decompose_secure_gates();
+        self.convert_secure_muxes();
+        // label the gates of the conversions, for the randomness domains of their gadgets
+        self.labels = self.propagate_labels();
 
         let mut replica_map = HashMap::new();
 
+        let starts = self
+            .secure_outputs()
+            .into_iter()
+            .chain(self.declassified().iter().copied())
+            .collect_vec();
         // FIXME do we need to traverse?
-        for start in self.secure_outputs() {
+        for start in starts {
             let mut dfs = Dfs::new(&self.graph, start);
             while let Some(nx) = dfs.next(Reversed(&self.graph)) {
                 let node = &self.graph[nx];
@@ -35,7 +62,11 @@ impl Masking for Circuit {
                     continue;
                 }
                 let replicas = match node.node_type {
+                    NodeType::Gate(GateType::Buf, false) if self.declassified().contains(&nx) => {
+                        self.recombine(num_shares, &nx)
+                    }
                     NodeType::Input
+                    | NodeType::Constant(_)
                     | NodeType::Output
                     | NodeType::Register
                     | NodeType::Gate(GateType::Xor(_) | GateType::Buf, _) => {
@@ -64,11 +95,18 @@ impl Masking for Circuit {
             for (sx, (src_port, dst_port)) in incoming_edges {
                 let src_node = &self.graph[*sx];
                 if !src_node.secure {
-                    for (rx, _, dst_offset) in replicas.iter() {
-                        self.connect(*sx, *src_port, *rx, dst_port + dst_offset);
+                    // a public operand of a linear function is a sharing with zero in the other
+                    // shares
+                    let (sx, src_port) = if self.is_linear_input(nx, *dst_port) {
+                        (self.const_node(false), 0)
+                    } else {
+                        (*sx, *src_port)
+                    };
+                    for (rx, dst_offset, _) in replicas.iter() {
+                        self.connect(sx, src_port, *rx, dst_port + dst_offset);
                     }
                 } else {
-                    println!("secure source: [{:?}] {:?}", sx, src_node);
+                    log::debug!("secure source: [{:?}] {:?}", sx, src_node);
                     if let Some(src_replicas) = replica_map.get(&sx) {
                         for ((rx, _, src_offset), (tx, dst_offset, _)) in
                             src_replicas.iter().zip(replicas)
@@ -82,6 +120,20 @@ impl Masking for Circuit {
             }
         }
 
+        // secrets arising inside the circuit get fresh shares, pre-shared inputs their share
+        let classified = self.classified().keys().copied().collect_vec();
+        for nx in classified {
+            if let Some(replicas) = replica_map.get(&nx) {
+                if self.preshared.contains_key(&nx) {
+                    self.place_share(nx, replicas);
+                } else {
+                    self.refresh(nx, replicas);
+                }
+            }
+        }
+
+        self.connect_randomness();
+
         // match node_type {
         //     NodeType::Gate(gate_type, invert) => match gate_type {
         //         GateType::Buf | GateType::Xor(_) => todo!(),
@@ -266,5 +318,219 @@ impl Masking for Circuit {
         //         }
         //     }
         // }
+        Ok(())
+    }
+}
+
+impl Circuit {
+    /// Check that the pre-shared inputs of every masked secret are its shares `0..num_shares`,
+    /// each exactly once
+    fn check_preshared(&self, num_shares: u8) -> Result<(), Error> {
+        let shares = self
+            .preshared
+            .iter()
+            .map(|(bx, &share)| (&self.classified[bx], share))
+            .filter(|(label, _)| self.is_masked(label))
+            .into_group_map();
+        for (label, shares) in shares.into_iter().sorted_by_key(|(l, _)| l.to_string()) {
+            let shares = shares.into_iter().sorted().collect_vec();
+            if !shares.iter().copied().eq(0..num_shares) {
+                return Err(SimpleError::new(format!(
+                    "the pre-shared inputs of {} are the shares {:?}, masking needs the shares 0..{}",
+                    label, shares, num_shares
+                ))
+                .into());
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether the output of the masked `nx` is linear in the input at `port`, which is then
+    /// added to one share only: XORs, the data of MUXes and registers, and outputs.
+    fn is_linear_input(&self, nx: &NodeIndex, port: NodePortId) -> bool {
+        match self.graph[*nx].node_type {
+            NodeType::Gate(GateType::Xor(_) | GateType::Buf, _) | NodeType::Output => true,
+            NodeType::Gate(GateType::Mux, _)
+            | NodeType::Gadget {
+                base_type: GateType::Mux,
+                ..
+            } => port != 0,
+            NodeType::Register => port == 1,
+            _ => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    use super::*;
+    use crate::circuit::{Label, NetlistAndLibrary};
+    use crate::netlist::json_netlist::{AttributeVal, Netlist};
+
+    /// `y = s ? b : a`, with the given inputs secure
+    fn mux(secure: [bool; 3]) -> Circuit {
+        let mut circuit = Circuit::default();
+        let mux = circuit.add_test_node(false, NodeType::Gate(GateType::Mux, false), "");
+        for (port, (name, secure)) in ["s", "a", "b"].into_iter().zip(secure).enumerate() {
+            let input = circuit.add_test_node(secure, NodeType::Input, name);
+            circuit.connect(input, 0, mux, port as NodePortId);
+        }
+        let y = circuit.add_test_node(false, NodeType::Output, "y");
+        circuit.connect(mux, 0, y, 0);
+        circuit
+    }
+
+    /// `y = !((a ^ 1) ^ !b) ^ c`, with a public constant and a secure constant `c`
+    fn inversions_and_constants() -> Circuit {
+        let mut circuit = Circuit::default();
+        let [a, b] = ["a", "b"].map(|name| circuit.add_test_node(true, NodeType::Input, name));
+        let one = circuit.add_test_node(false, NodeType::Constant(true), "");
+        let xor = circuit.add_test_node(false, NodeType::Gate(GateType::Xor(2), false), "");
+        let not = circuit.add_test_node(false, NodeType::Gate(GateType::Buf, true), "");
+        let xnor = circuit.add_test_node(false, NodeType::Gate(GateType::Xor(2), true), "");
+        let c = circuit.add_test_node(true, NodeType::Constant(false), "");
+        let y = circuit.add_test_node(false, NodeType::Gate(GateType::Xor(2), false), "");
+        let output = circuit.add_test_node(false, NodeType::Output, "y");
+        circuit.connect(a, 0, xor, 0);
+        circuit.connect(one, 0, xor, 1);
+        circuit.connect(b, 0, not, 0);
+        circuit.connect(xor, 0, xnor, 0);
+        circuit.connect(not, 0, xnor, 1);
+        circuit.connect(xnor, 0, y, 0);
+        circuit.connect(c, 0, y, 1);
+        circuit.connect(y, 0, output, 0);
+        circuit
+    }
+
+    /// `y = (k_0 ^ k_1 ^ ..) & x`, where the `num_keys` inputs `k_i` deliver the shares of the
+    /// secret `key`
+    fn preshared_key(num_keys: u8) -> Circuit {
+        let mut circuit = Circuit::default();
+        let x = circuit.add_test_node(true, NodeType::Input, "x");
+        let and = circuit.add_test_node(false, NodeType::Gate(GateType::And(2), false), "");
+        let y = circuit.add_test_node(false, NodeType::Output, "y");
+        let xor = circuit.add_test_node(false, NodeType::Gate(GateType::Xor(num_keys), false), "");
+        for share in 0..num_keys {
+            let k = circuit.add_test_node(false, NodeType::Input, &format!("k_{}", share));
+            circuit.connect(k, 0, xor, share as NodePortId);
+            circuit.preshare(k, share, Label::secret("key"));
+        }
+        circuit.connect(xor, 0, and, 0);
+        circuit.connect(x, 0, and, 1);
+        circuit.connect(and, 0, y, 0);
+        circuit
+    }
+
+    #[test]
+    fn invert_one_share() -> Result<(), crate::circuit::Error> {
+        let circuit = inversions_and_constants();
+        let mut rng = StdRng::seed_from_u64(0);
+        // an even number of shares cancels inversions and constants applied to every share
+        for order in 1..=3 {
+            let mut masked = circuit.clone();
+            masked.mask(order)?;
+            let inverted = masked
+                .graph
+                .node_weights()
+                .filter(|node| matches!(node.node_type, NodeType::Gate(_, true)))
+                .count();
+            assert_eq!(inverted, 2, "order {}", order);
+            let mismatch = circuit.check_equivalence(&masked, 4, 64, &mut rng)?;
+            assert_eq!(mismatch, None, "order {}", order);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn preshared_inputs() -> Result<(), crate::circuit::Error> {
+        let circuit = preshared_key(2);
+        let netlist = Netlist::try_from(&circuit)?;
+        let masq = |net: &str| netlist.modules[""].netnames[net].attributes.get("MASQ");
+        assert_eq!(
+            masq("k_1"),
+            Some(&AttributeVal::String("share:1:key".to_owned()))
+        );
+        let reloaded = Circuit::try_from(&NetlistAndLibrary::new(netlist))?;
+        let mut rng = StdRng::seed_from_u64(0);
+        for circuit in [circuit, reloaded] {
+            assert_eq!(circuit.classified().len(), 2);
+            for gadget in [GadgetKind::Isw, GadgetKind::DomIndep] {
+                let order = 1;
+                let mut masked = circuit.clone();
+                masked.mask_with(order, gadget)?;
+                // the key is not shared again
+                let inputs = masked
+                    .inputs
+                    .iter()
+                    .filter(|&&nx| masked.graph[nx].node_type == NodeType::Input)
+                    .filter_map(|&nx| masked.graph[nx].name.clone())
+                    .sorted()
+                    .collect_vec();
+                let expected = ["k_0".to_owned(), "k_1".to_owned()]
+                    .into_iter()
+                    .chain((0..=order).map(|s| format!("x_s{}", s)))
+                    .collect_vec();
+                assert_eq!(inputs, expected);
+                assert!(masked.classified().is_empty());
+                let mismatch = circuit.check_equivalence(&masked, 4, 64, &mut rng)?;
+                assert_eq!(mismatch, None, "{:?}", gadget);
+                masked.expand_gadgets();
+                let mismatch = circuit.check_equivalence(&masked, 4, 64, &mut rng)?;
+                assert_eq!(mismatch, None, "{:?}", gadget);
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn preshared_inputs_must_match_order() {
+        // order 1 masks with 2 shares, order 2 with 3
+        assert!(preshared_key(3).mask(1).is_err());
+        assert!(preshared_key(2).mask(2).is_err());
+        assert!(preshared_key(3).mask(2).is_ok());
+        // the shares 0 and 2 of a secret with 3 shares
+        let mut circuit = preshared_key(2);
+        for share in circuit.preshared.values_mut().filter(|share| **share == 1) {
+            *share = 2;
+        }
+        assert!(circuit.clone().mask(1).is_err());
+        assert!(circuit.mask(2).is_err());
+        // public keys are not masked
+        let mut circuit = preshared_key(3);
+        circuit.select_secrets(["iv"]);
+        assert!(circuit.mask(1).is_ok());
+    }
+
+    #[test]
+    fn secure_mux() -> Result<(), crate::circuit::Error> {
+        let mut rng = StdRng::seed_from_u64(0);
+        // secure inputs -> number of gadgets
+        let cases = [
+            ([false, true, true], 0),
+            ([false, true, false], 0),
+            ([true, false, false], 0),
+            ([true, true, false], 1),
+            ([true, true, true], 1),
+        ];
+        for (secure, num_gadgets) in cases {
+            for order in 1..=2 {
+                let circuit = mux(secure);
+                let mut masked = circuit.clone();
+                masked.mask_with(order, GadgetKind::DomIndep)?;
+                let gadgets = masked
+                    .graph
+                    .node_weights()
+                    .filter(|node| matches!(node.node_type, NodeType::Gadget { .. }))
+                    .count();
+                assert_eq!(gadgets, num_gadgets, "{:?}", secure);
+                masked.expand_gadgets();
+                let mismatch = circuit.check_equivalence(&masked, 4, 64, &mut rng)?;
+                assert_eq!(mismatch, None, "{:?}, order {}", secure, order);
+            }
+        }
+        Ok(())
     }
 }
@@ -1,22 +1,42 @@
 mod cell_library;
 mod circuit_impl;
 mod dot;
+mod equivalence;
+mod expand;
 mod from_netlist;
+mod gadget;
 mod into_netlist;
+mod latency;
+mod liberty;
 mod masking;
 mod node;
+mod randomness;
+mod simulation;
+mod stats;
+mod taint;
+#[cfg(test)]
+mod test_utils;
+mod verilog;
 
 use petgraph::stable_graph::{self, StableDiGraph};
 use simple_error::SimpleError;
 
+pub use cell_library::CellLibrary;
 pub use dot::Dot;
+pub use equivalence::Mismatch;
 pub use from_netlist::NetlistAndLibrary;
+pub use gadget::{Gadget, GadgetKind, Op, Security, ShareNetwork, Wire};
 pub use masking::Masking;
+pub use randomness::RandomnessReuse;
+pub use simulation::{Simulator, Trace};
+pub use stats::Stats;
+pub use taint::{Label, SecretReach, SecretReport, Taint};
+pub use verilog::Verilog;
 
 use node::Blackbox;
 use node::{Node, NodeBuilder, NodePortId, NodeType};
 
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeSet, HashMap, HashSet};
 
 type NodeIndex = stable_graph::NodeIndex;
 
@@ -30,6 +50,12 @@ pub enum Error {
     SerdeError(#[from] serde_json::Error),
     #[error(transparent)]
     SimpleError(#[from] SimpleError),
+    #[error(transparent)]
+    VerilogError(#[from] crate::netlist::verilog::Error),
+    #[error(transparent)]
+    LibertyError(#[from] liberty::Error),
+    #[error(transparent)]
+    ConfigError(#[from] crate::config::Error),
 }
 
 #[derive(Debug, Clone, Default)]
@@ -37,6 +63,10 @@ pub struct Circuit {
     pub name: String,
     graph: StableDiGraph<Node, (NodePortId, NodePortId)>, // edges store src port and dst port
     input_ordering_map: HashMap<NodeType, Vec<String>>,
+    output_ordering_map: HashMap<NodeType, Vec<String>>,
+    cell_type_names: HashMap<NodeType, String>,
+    /// library of the netlist, for the cells and pins of nodes added to the circuit
+    cell_library: CellLibrary,
     blackboxes: HashMap<String, Blackbox>,
     // blackbox_impls: HashMap<String, Circuit>,
     inputs: HashSet<NodeIndex>,
@@ -44,4 +74,27 @@ pub struct Circuit {
     outputs: HashSet<NodeIndex>,
     registers: HashSet<NodeIndex>,
     consts: [Option<NodeIndex>; 2],
+    /// buffers where a secure value is deliberately made public (MASQ "declassify"), which
+    /// stop the secure taint and recombine the shares when masking
+    declassified: HashSet<NodeIndex>,
+    /// buffers where a value inside the circuit becomes a secret (MASQ label on an internal net
+    /// or cell), which start a secure taint and are shared with fresh randomness when masking
+    classified: HashMap<NodeIndex, Label>,
+    /// classified buffers of inputs which deliver a share of a secret rather than its value
+    /// (MASQ "share:1:key"), with the index of the share they are wired to when masking
+    preshared: HashMap<NodeIndex, u8>,
+    /// security labels, of the secret inputs and once propagated of all nodes carrying secrets
+    labels: HashMap<NodeIndex, Label>,
+    /// secrets to mask, all if `None`
+    masked_secrets: Option<BTreeSet<String>>,
+    /// scheme of the gadgets of a masked circuit
+    gadget: GadgetKind,
+    /// random inputs which masking allocated to the gadgets, replaced when reusing randomness
+    gadget_random_bits: HashSet<NodeIndex>,
+    /// cycles by which the outputs of a masked circuit lag behind the original circuit
+    latency: usize,
+    /// outputs of a masked circuit which lag behind by `latency`
+    delayed_outputs: HashSet<NodeIndex>,
+    /// registers on loops through gadgets, which restart at latency 0
+    unaligned_registers: HashSet<NodeIndex>,
 }
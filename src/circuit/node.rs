@@ -65,6 +65,11 @@ pub struct Blackbox {
 #[derive(Clone, PartialEq, Eq, Hash, Debug)]
 pub enum NodeType {
     Input,
+    /// Public control input (MASQ "constant"), which never carries secrets and is stable
+    /// while the masked datapath is computing. It is never shared.
+    Control,
+    /// Fresh random bit for the gadgets (MASQ "random")
+    Random,
     Clock,
     Reset,
     Gate(GateType, bool),
@@ -132,6 +137,8 @@ impl Display for NodeType {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             NodeType::Input => f.write_str("IN"),
+            NodeType::Control => f.write_str("CONTROL"),
+            NodeType::Random => f.write_str("RAND"),
             NodeType::Clock => f.write_str("CLOCK"),
             NodeType::Reset => f.write_str("Reset"),
             NodeType::Gate(gate_type, inv) => {
@@ -164,6 +171,8 @@ impl NodeType {
     pub fn has_output(&self) -> bool {
         match self {
             NodeType::Input
+            | NodeType::Control
+            | NodeType::Random
             | NodeType::Clock
             | NodeType::Gate { .. }
             | NodeType::Register
@@ -171,6 +180,41 @@ impl NodeType {
             _ => false,
         }
     }
+
+    /// Number of operands of a gadget, each connected to `num_shares` input ports.
+    /// Any further input ports of a gadget are its fresh random bits.
+    pub fn num_gadget_operands(&self) -> Option<usize> {
+        match self {
+            NodeType::Gadget { base_type, .. } => Some(match base_type {
+                GateType::And(n) | GateType::Or(n) | GateType::Xor(n) => *n as usize,
+                GateType::Mux => 3,
+                GateType::Buf => 1,
+            }),
+            _ => None,
+        }
+    }
+
+    /// Name of the cell (module) instantiated for this node in an exported netlist.
+    /// `None` for ports and constants which do not map to cells.
+    pub fn cell_type_name(&self) -> Option<String> {
+        match self {
+            NodeType::Gate(..) => Some(self.to_string()),
+            NodeType::Register => Some("DFF".to_owned()),
+            NodeType::Blackbox(bb) => Some(bb.clone()),
+            NodeType::Gadget {
+                base_type,
+                invert,
+                num_shares,
+            } => Some(format!(
+                "masq_{}_gadget_{}",
+                NodeType::Gate(*base_type, *invert)
+                    .to_string()
+                    .to_lowercase(),
+                num_shares
+            )),
+            _ => None,
+        }
+    }
 }
 
 impl TryFrom<(&CellLibrary, &str)> for NodeType {
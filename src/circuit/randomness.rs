@@ -0,0 +1,367 @@
+use std::collections::HashMap;
+use std::fmt::Display;
+
+use itertools::Itertools;
+use petgraph::stable_graph::StableDiGraph;
+use petgraph::visit::{VisitMap, Visitable};
+use petgraph::Direction;
+use simple_error::SimpleError;
+
+use super::node::{GateType, Node, NodePortId, NodeType};
+use super::{Circuit, Error, NodeIndex};
+
+/// bit set of the nodes of a circuit graph
+type NodeSet = <StableDiGraph<Node, (NodePortId, NodePortId)> as Visitable>::Map;
+
+/// Fresh random bits per cycle before and after `reuse_randomness`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RandomnessReuse {
+    /// bits with a separate allocation for every gadget
+    pub naive: usize,
+    pub optimized: usize,
+}
+
+impl RandomnessReuse {
+    pub fn saved(&self) -> usize {
+        self.naive - self.optimized
+    }
+}
+
+impl Display for RandomnessReuse {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} random bits per cycle, saved {} of {}",
+            self.optimized,
+            self.saved(),
+            self.naive
+        )
+    }
+}
+
+impl Circuit {
+    fn gadget_nodes(&self) -> Vec<NodeIndex> {
+        self.graph
+            .node_indices()
+            .filter(|&nx| matches!(self.graph[nx].node_type, NodeType::Gadget { .. }))
+            .collect()
+    }
+
+    /// Name of the random input of the gadget `gx`: `rand`, or `rand_key` for the gadgets of
+    /// the secret `key`, so that each secret has its own randomness
+    fn randomness_domain(&self, gx: NodeIndex) -> String {
+        let label = self.label(gx);
+        if label.is_public() || label.to_string() == "secure" {
+            "rand".to_owned()
+        } else {
+            format!("rand_{}", label.secrets().join("_"))
+        }
+    }
+
+    /// Connect every gadget to its own bits of the `rand` input, after its share inputs.
+    pub(crate) fn connect_randomness(&mut self) {
+        let groups = self
+            .gadget_nodes()
+            .into_iter()
+            .map(|gx| vec![gx])
+            .collect_vec();
+        self.allocate_randomness(&groups);
+    }
+
+    /// Share random bits between gadgets of the same randomness domain, only where this keeps
+    /// a circuit masked at order 1 first-order probing secure: a single probe, also extended by
+    /// glitches up to the last registers, observes values of the secure cone of a gadget, so it
+    /// can not combine two gadgets using the same bits if
+    /// - their cones of secure nodes are disjoint, both in their fan-in and their fan-out, and
+    /// - neither of them feeds a public node, such as the recombined shares at a
+    ///   declassification point, which would combine their outputs outside of the cones.
+    ///
+    /// Gadgets which are recombined keep their own bits. Returns an error for circuits masked
+    /// at a higher order, where probes on two gadgets can cancel the shared bits.
+    pub fn reuse_randomness(&mut self) -> Result<RandomnessReuse, Error> {
+        let first_order_shares = self.gadget.gadget().num_shares(1);
+        let gadgets = self.gadget_nodes();
+        let higher_order = gadgets.iter().any(|&gx| {
+            matches!(self.graph[gx].node_type,
+                NodeType::Gadget { num_shares, .. } if num_shares as usize > first_order_shares)
+        });
+        if higher_order {
+            return Err(SimpleError::new(
+                "randomness can only be reused in circuits masked at order 1",
+            )
+            .into());
+        }
+        let gadgets = gadgets
+            .into_iter()
+            .sorted_by_key(|&gx| {
+                std::cmp::Reverse(self.gadget_randomness(&self.graph[gx].node_type))
+            })
+            .collect_vec();
+        let naive = gadgets
+            .iter()
+            .map(|&gx| self.gadget_randomness(&self.graph[gx].node_type))
+            .sum();
+
+        // first fit: a gadget joins the first group of its domain it shares no secure node with
+        let mut groups: Vec<(Vec<NodeIndex>, String, NodeSet, NodeSet)> = Vec::new();
+        let mut recombined = Vec::new();
+        for gx in gadgets {
+            let domain = self.randomness_domain(gx);
+            let fan_in = self.secure_cone(gx, Direction::Incoming);
+            let fan_out = self.secure_cone(gx, Direction::Outgoing);
+            if self.feeds_public(&fan_out) {
+                recombined.push(vec![gx]);
+                continue;
+            }
+            match groups.iter_mut().find(|(_, d, i, o)| {
+                *d == domain && fan_in.is_disjoint(i) && fan_out.is_disjoint(o)
+            }) {
+                Some((members, _, i, o)) => {
+                    members.push(gx);
+                    i.union_with(&fan_in);
+                    o.union_with(&fan_out);
+                }
+                None => groups.push((vec![gx], domain, fan_in, fan_out)),
+            }
+        }
+        let groups = groups
+            .into_iter()
+            .map(|(members, _, _, _)| members)
+            .chain(recombined)
+            .collect_vec();
+        let optimized = self.allocate_randomness(&groups);
+        Ok(RandomnessReuse { naive, optimized })
+    }
+
+    /// whether a node of the secure `cone` drives a public node, e.g. where shares are recombined
+    fn feeds_public(&self, cone: &NodeSet) -> bool {
+        cone.ones().map(NodeIndex::new).any(|nx| {
+            self.graph
+                .neighbors_directed(nx, Direction::Outgoing)
+                .any(|n| !self.graph[n].secure)
+        })
+    }
+
+    /// secure nodes reachable from `start` in `direction`, including `start`
+    fn secure_cone(&self, start: NodeIndex, direction: Direction) -> NodeSet {
+        let mut cone = self.graph.visit_map();
+        let mut stack = vec![start];
+        while let Some(nx) = stack.pop() {
+            if !cone.visit(nx) {
+                continue;
+            }
+            stack.extend(
+                self.graph
+                    .neighbors_directed(nx, direction)
+                    .filter(|&n| self.graph[n].secure && !cone.is_visited(&n)),
+            );
+        }
+        cone
+    }
+
+    /// Share the secret classified at `nx` with fresh random bits `r1, r2, ...`: share 0
+    /// becomes `x0 ^ r1 ^ r2 ^ ...` and share `i` of the `replicas` becomes `xi ^ ri`, where
+    /// `xi` are the shares of the value driving `nx`, 0 for `i > 0` if it is public.
+    pub(crate) fn refresh(
+        &mut self,
+        nx: NodeIndex,
+        replicas: &[(NodeIndex, NodePortId, NodePortId)],
+    ) {
+        let domain = self.randomness_domain(nx);
+        let prefix = format!("{}_share[", domain);
+        let first = self
+            .random_inputs()
+            .into_iter()
+            .filter(|&rx| {
+                self.graph[rx]
+                    .name
+                    .as_ref()
+                    .is_some_and(|n| n.starts_with(&prefix))
+            })
+            .count();
+        let num_shares = replicas.len() + 1;
+        self.graph[nx].node_type = NodeType::Gate(GateType::Xor(num_shares as u8), false);
+        for (i, &(rx, _, _)) in replicas.iter().enumerate() {
+            let bit = self.add_node(Node {
+                secure: false,
+                node_type: NodeType::Random,
+                name: Some(format!("{}{}]", prefix, first + i)),
+            });
+            self.graph[rx].node_type = self.two_input_gate(GateType::Xor, false);
+            self.connect(bit, 0, nx, (i + 1) as NodePortId);
+            self.connect(bit, 0, rx, 1);
+        }
+        self.classified.remove(&nx);
+    }
+
+    /// Replace the random inputs of the gadgets: the gadgets of each group get the same new bits
+    /// of the random input of their domain. Returns the number of bits allocated to gadgets.
+    fn allocate_randomness(&mut self, groups: &[Vec<NodeIndex>]) -> usize {
+        // keep the bits of the shared secrets (see [Circuit::refresh]) and the random inputs of
+        // the design
+        for rx in std::mem::take(&mut self.gadget_random_bits) {
+            self.graph.remove_node(rx);
+            self.inputs.remove(&rx);
+        }
+        // domain -> bits allocated so far
+        let mut num_random: HashMap<String, usize> = HashMap::new();
+        for group in groups {
+            let Some(&first) = group.first() else {
+                continue;
+            };
+            let domain = self.randomness_domain(first);
+            let mut bits: Vec<NodeIndex> = Vec::new();
+            for &gx in group {
+                let node_type = &self.graph[gx].node_type;
+                let (NodeType::Gadget { num_shares, .. }, Some(num_operands)) =
+                    (node_type, node_type.num_gadget_operands())
+                else {
+                    continue;
+                };
+                let first_port = *num_shares as usize * num_operands;
+                for k in 0..self.gadget_randomness(node_type) {
+                    if k == bits.len() {
+                        let index = num_random.entry(domain.clone()).or_default();
+                        let rx = self.add_node(Node {
+                            secure: false,
+                            node_type: NodeType::Random,
+                            name: Some(format!("{}[{}]", domain, index)),
+                        });
+                        *index += 1;
+                        self.gadget_random_bits.insert(rx);
+                        bits.push(rx);
+                    }
+                    self.connect(bits[k], 0, gx, (first_port + k) as NodePortId);
+                }
+            }
+        }
+        num_random.values().sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    use super::*;
+    use crate::circuit::node::GateType;
+    use crate::circuit::{GadgetKind, Masking};
+
+    /// `y = a & b` and `z = c & d`, or `y = (a & b) ^ (c & d)` if `combined`
+    fn two_ands(combined: bool) -> Circuit {
+        let mut circuit = Circuit::default();
+        let ands = [["a", "b"], ["c", "d"]].map(|names| {
+            let and = circuit.add_test_node(false, NodeType::Gate(GateType::And(2), false), "");
+            for (port, name) in names.into_iter().enumerate() {
+                let input = circuit.add_test_node(true, NodeType::Input, name);
+                circuit.connect(input, 0, and, port as NodePortId);
+            }
+            and
+        });
+        if combined {
+            let xor = circuit.add_test_node(false, NodeType::Gate(GateType::Xor(2), false), "");
+            let y = circuit.add_test_node(false, NodeType::Output, "y");
+            circuit.connect(ands[0], 0, xor, 0);
+            circuit.connect(ands[1], 0, xor, 1);
+            circuit.connect(xor, 0, y, 0);
+        } else {
+            for (and, name) in ands.into_iter().zip(["y", "z"]) {
+                let output = circuit.add_test_node(false, NodeType::Output, name);
+                circuit.connect(and, 0, output, 0);
+            }
+        }
+        circuit
+    }
+
+    #[test]
+    fn reuse_independent_gadgets() -> Result<(), Error> {
+        let circuit = two_ands(false);
+        let mut masked = circuit.clone();
+        masked.mask_with(1, GadgetKind::Isw)?;
+        let naive = masked.random_inputs().len();
+        let reuse = masked.reuse_randomness()?;
+        assert_eq!(reuse.naive, naive);
+        assert_eq!(reuse.optimized, naive / 2);
+        assert_eq!(reuse.saved(), naive / 2);
+
+        masked.expand_gadgets();
+        let mut rng = StdRng::seed_from_u64(0);
+        assert_eq!(circuit.check_equivalence(&masked, 4, 64, &mut rng)?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn reuse_with_design_randomness() {
+        // a random input of the design itself, which is not a gadget bit
+        let mut masked = two_ands(false);
+        let noise = masked.add_test_node(false, NodeType::Random, "noise");
+        let n = masked.add_test_node(false, NodeType::Output, "n");
+        masked.connect(noise, 0, n, 0);
+        masked.mask_with(1, GadgetKind::Isw).unwrap();
+        let naive = masked.random_inputs().len() - 1;
+        let reuse = masked.reuse_randomness().unwrap();
+        assert_eq!(reuse.naive, naive);
+        assert_eq!(reuse.optimized, naive / 2);
+        assert_eq!(masked.random_inputs().len(), naive / 2 + 1);
+    }
+
+    #[test]
+    fn reuse_keeps_unconnected_design_randomness() -> Result<(), Error> {
+        // a random input of the design which drives nothing, like the bits of a gadget would
+        // after they are replaced
+        let mut masked = two_ands(false);
+        let spare = masked.add_test_node(false, NodeType::Random, "spare");
+        masked.mask_with(1, GadgetKind::Isw)?;
+        let naive = masked.random_inputs().len() - 1;
+        let reuse = masked.reuse_randomness()?;
+        assert_eq!(reuse.naive, naive);
+        assert_eq!(reuse.optimized, naive / 2);
+        assert!(masked.inputs.contains(&spare));
+        assert_eq!(masked.graph[spare].name.as_deref(), Some("spare"));
+        assert_eq!(masked.random_inputs().len(), naive / 2 + 1);
+        Ok(())
+    }
+
+    #[test]
+    fn no_reuse_for_combined_gadgets() {
+        let mut masked = two_ands(true);
+        masked.mask_with(1, GadgetKind::Isw).unwrap();
+        let naive = masked.random_inputs().len();
+        let reuse = masked.reuse_randomness().unwrap();
+        assert_eq!(reuse.saved(), 0);
+        assert_eq!(masked.random_inputs().len(), naive);
+    }
+
+    #[test]
+    fn no_reuse_at_higher_order_or_for_recombined_gadgets() -> Result<(), Error> {
+        let mut masked = two_ands(false);
+        masked.mask_with(2, GadgetKind::Isw)?;
+        assert!(masked.reuse_randomness().is_err());
+
+        // the AND of `y` is declassified, so its shares are recombined
+        let mut circuit = two_ands(false);
+        let y = circuit
+            .outputs
+            .iter()
+            .copied()
+            .find(|&ox| circuit.graph[ox].name.as_deref() == Some("y"))
+            .unwrap();
+        let and = circuit
+            .graph
+            .neighbors_directed(y, Direction::Incoming)
+            .next()
+            .unwrap();
+        circuit.declassify(and, 0);
+        let mut masked = circuit.clone();
+        masked.mask_with(1, GadgetKind::Isw)?;
+        let naive = masked.random_inputs().len();
+        assert_eq!(masked.reuse_randomness()?.saved(), 0);
+        assert_eq!(masked.random_inputs().len(), naive);
+
+        masked.expand_gadgets();
+        let mut rng = StdRng::seed_from_u64(0);
+        assert_eq!(circuit.check_equivalence(&masked, 4, 64, &mut rng)?, None);
+        Ok(())
+    }
+}
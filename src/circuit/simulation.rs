@@ -0,0 +1,308 @@
+use std::collections::{BTreeMap, HashMap, VecDeque};
+
+use petgraph::algo::toposort;
+use petgraph::visit::{EdgeFiltered, EdgeRef};
+use petgraph::Direction;
+use simple_error::SimpleError;
+
+use crate::logic::{Logic, LogicVec, LogicVector};
+
+use super::node::{GateType, NodePortId, NodeType};
+use super::{Circuit, Error, NodeIndex};
+
+/// name -> value
+type Values = BTreeMap<String, LogicVec>;
+
+/// Values of the outputs and registers in each simulated cycle
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Trace {
+    /// output name -> value in each cycle
+    pub outputs: BTreeMap<String, Vec<LogicVec>>,
+    /// register name -> state in each cycle, before the clock edge ending the cycle
+    pub registers: BTreeMap<String, Vec<LogicVec>>,
+}
+
+/// Cycle-based simulator of a `Circuit`.
+/// Every value is a `LogicVec` of `width` bits, one bit for each of the test vectors which are
+/// simulated in parallel.
+pub struct Simulator<'c> {
+    circuit: &'c Circuit,
+    width: usize,
+    /// combinational evaluation order, ending each cycle's paths at registers and outputs
+    order: Vec<NodeIndex>,
+    /// node -> its drivers, indexed by input port
+    drivers: HashMap<NodeIndex, Vec<Option<(NodeIndex, NodePortId)>>>,
+    /// node -> value of each of its output ports in the current cycle
+    values: HashMap<NodeIndex, Vec<LogicVec>>,
+    /// register -> current state
+    state: HashMap<NodeIndex, LogicVec>,
+    /// gadget -> output shares of the last cycles, oldest first
+    pipelines: HashMap<NodeIndex, VecDeque<Vec<LogicVec>>>,
+}
+
+fn node_name(circuit: &Circuit, nx: NodeIndex, prefix: &str) -> String {
+    circuit.graph[nx]
+        .name
+        .clone()
+        .unwrap_or_else(|| format!("{}_{}", prefix, nx.index()))
+}
+
+impl<'c> Simulator<'c> {
+    /// All registers start as X. Fails if the circuit has a combinational loop.
+    pub fn new(circuit: &'c Circuit, width: usize) -> Result<Self, Error> {
+        let graph = &circuit.graph;
+        let combinational =
+            EdgeFiltered::from_fn(graph, |e| graph[e.target()].node_type != NodeType::Register);
+        let order = toposort(&combinational, None).map_err(|cycle| {
+            SimpleError::new(format!(
+                "combinational loop through node {}",
+                node_name(circuit, cycle.node_id(), "node")
+            ))
+        })?;
+        let drivers = graph
+            .node_indices()
+            .map(|nx| {
+                let mut inputs = vec![None; circuit.num_input_ports(&nx)];
+                for e in graph.edges_directed(nx, Direction::Incoming) {
+                    let (src_port, dst_port) = *e.weight();
+                    inputs[dst_port as usize] = Some((e.source(), src_port));
+                }
+                (nx, inputs)
+            })
+            .collect();
+        let state = circuit
+            .registers
+            .iter()
+            .map(|&rx| (rx, LogicVec::repeat(Logic::X, width)))
+            .collect();
+        let pipelines = graph
+            .node_indices()
+            .filter_map(|nx| {
+                let node_type = &graph[nx].node_type;
+                let NodeType::Gadget { num_shares, .. } = node_type else {
+                    return None;
+                };
+                let shares = vec![LogicVec::repeat(Logic::X, width); *num_shares as usize];
+                let latency = circuit.gadget_latency(node_type);
+                Some((nx, VecDeque::from(vec![shares; latency])))
+            })
+            .collect();
+        Ok(Self {
+            circuit,
+            width,
+            order,
+            drivers,
+            values: HashMap::new(),
+            state,
+            pipelines,
+        })
+    }
+
+    fn input(&self, nx: NodeIndex, port: usize) -> LogicVec {
+        self.drivers[&nx]
+            .get(port)
+            .copied()
+            .flatten()
+            .and_then(|(sx, sp)| self.values.get(&sx)?.get(sp as usize).cloned())
+            .unwrap_or_else(|| LogicVec::repeat(Logic::X, self.width))
+    }
+
+    fn inputs(&self, nx: NodeIndex) -> Vec<LogicVec> {
+        (0..self.drivers[&nx].len())
+            .map(|port| self.input(nx, port))
+            .collect()
+    }
+
+    fn eval_gate(&self, gate_type: GateType, invert: bool, inputs: &[LogicVec]) -> LogicVec {
+        let x = || LogicVec::repeat(Logic::X, self.width);
+        let fold = |f: fn(&LogicVec, &LogicVec) -> LogicVec| {
+            inputs
+                .iter()
+                .skip(1)
+                .fold(inputs.first().map_or_else(x, LogicVec::buf), |acc, v| {
+                    f(&acc, v)
+                })
+        };
+        let value = match gate_type {
+            GateType::Buf => fold(|a, _| a.clone()),
+            GateType::And(_) => fold(LogicVec::and),
+            GateType::Or(_) => fold(LogicVec::or),
+            GateType::Xor(_) => fold(LogicVec::xor),
+            GateType::Mux => match inputs {
+                [s, a, b] => s.mux(a, b),
+                _ => x(),
+            },
+        };
+        if invert {
+            value.not()
+        } else {
+            value
+        }
+    }
+
+    /// Values of the output shares of a gadget. The gadget is modeled only functionally, by its
+    /// function and latency: the first share is the gate applied to the recombined inputs and the
+    /// other shares are 0. Simulating or checking the equivalence of a circuit with unexpanded
+    /// gadgets thus does not test the gadgets themselves, expand them for that.
+    fn eval_gadget(
+        &self,
+        base_type: GateType,
+        invert: bool,
+        num_shares: u8,
+        num_in_ports: usize,
+        inputs: &[LogicVec],
+    ) -> Vec<LogicVec> {
+        let num_shares = num_shares as usize;
+        let recombined = (0..num_in_ports)
+            .map(|i| {
+                (1..num_shares).fold(inputs[i].buf(), |acc, s| {
+                    acc.xor(&inputs[s * num_in_ports + i])
+                })
+            })
+            .collect::<Vec<_>>();
+        let mut shares = vec![self.eval_gate(base_type, invert, &recombined)];
+        shares.resize(num_shares, LogicVec::repeat(Logic::Zero, self.width));
+        shares
+    }
+
+    /// Simulate one clock cycle: apply `inputs` (by input node name, missing inputs are X),
+    /// evaluate the combinational logic and then clock the registers.
+    /// Returns the values of the outputs and the register states during the cycle.
+    pub fn step(&mut self, inputs: &HashMap<String, LogicVec>) -> Result<(Values, Values), Error> {
+        let circuit = self.circuit;
+        let graph = &circuit.graph;
+        if let Some((name, v)) = inputs.iter().find(|(_, v)| v.len() != self.width) {
+            return Err(SimpleError::new(format!(
+                "input {} has {} bits instead of {}",
+                name,
+                v.len(),
+                self.width
+            ))
+            .into());
+        }
+        self.values.clear();
+        for &nx in self.order.iter() {
+            let node = &graph[nx];
+            let outputs = match node.node_type {
+                NodeType::Input | NodeType::Control | NodeType::Random | NodeType::Reset => {
+                    vec![node
+                        .name
+                        .as_ref()
+                        .and_then(|name| inputs.get(name))
+                        .map_or_else(|| LogicVec::repeat(Logic::X, self.width), LogicVec::buf)]
+                }
+                NodeType::Clock => vec![LogicVec::repeat(Logic::X, self.width)],
+                NodeType::Constant(v) => vec![LogicVec::repeat(Logic::from(v), self.width)],
+                NodeType::Register => vec![self.state[&nx].clone()],
+                NodeType::Gate(gate_type, invert) => {
+                    vec![self.eval_gate(gate_type, invert, &self.inputs(nx))]
+                }
+                NodeType::Gadget {
+                    base_type,
+                    invert,
+                    num_shares,
+                } => {
+                    let shares = self.eval_gadget(
+                        base_type,
+                        invert,
+                        num_shares,
+                        node.node_type.num_gadget_operands().unwrap_or(0),
+                        &self.inputs(nx),
+                    );
+                    let pipeline = self.pipelines.get_mut(&nx).unwrap();
+                    pipeline.push_back(shares);
+                    pipeline.pop_front().unwrap()
+                }
+                NodeType::Blackbox(_) => {
+                    let num_outputs = graph
+                        .edges_directed(nx, Direction::Outgoing)
+                        .map(|e| e.weight().0 as usize + 1)
+                        .max()
+                        .unwrap_or(0);
+                    vec![LogicVec::repeat(Logic::X, self.width); num_outputs]
+                }
+                NodeType::Output => vec![self.input(nx, 0)],
+            };
+            self.values.insert(nx, outputs);
+        }
+        let outputs = circuit
+            .outputs
+            .iter()
+            .map(|&ox| (node_name(circuit, ox, "out"), self.values[&ox][0].clone()))
+            .collect();
+        let registers = circuit
+            .registers
+            .iter()
+            .map(|&rx| (node_name(circuit, rx, "reg"), self.state[&rx].clone()))
+            .collect();
+        // clock edge: registers take the value of their D input
+        for &rx in circuit.registers.iter() {
+            let d = self.input(rx, 1);
+            self.state.insert(rx, d);
+        }
+        Ok((outputs, registers))
+    }
+
+    /// Simulate a cycle for each of the `inputs`, see [Simulator::step].
+    pub fn run(&mut self, inputs: &[HashMap<String, LogicVec>]) -> Result<Trace, Error> {
+        let mut trace = Trace::default();
+        for cycle_inputs in inputs {
+            let (outputs, registers) = self.step(cycle_inputs)?;
+            for (name, v) in outputs {
+                trace.outputs.entry(name).or_default().push(v);
+            }
+            for (name, v) in registers {
+                trace.registers.entry(name).or_default().push(v);
+            }
+        }
+        Ok(trace)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use super::*;
+    use crate::circuit::test_utils::simple_1;
+
+    #[test]
+    fn simulate_simple_1() -> Result<(), Box<dyn std::error::Error>> {
+        let circuit = simple_1();
+        // 4 parallel vectors, with in_data = 0, 1, 2, 3
+        let cycle = |rst: &str, in_valid: &str, out_ready: &str| {
+            [
+                ("rst", rst),
+                ("in_valid", in_valid),
+                ("out_ready", out_ready),
+                ("in_data[0]", "1010"),
+                ("in_data[1]", "1100"),
+            ]
+            .into_iter()
+            .map(|(name, v)| (name.to_owned(), LogicVec::from_str(v).unwrap()))
+            .collect::<HashMap<_, _>>()
+        };
+        let mut sim = Simulator::new(&circuit, 4)?;
+        let trace = sim.run(&[
+            cycle("1111", "0000", "1111"),
+            cycle("0000", "1111", "0000"),
+            cycle("0000", "0000", "0000"),
+        ])?;
+        let output = |name: &str| {
+            trace.outputs[name]
+                .iter()
+                .map(|v| v.to_string())
+                .collect::<Vec<_>>()
+        };
+        // the data registers are not reset
+        assert_eq!(output("out_data[0]"), ["xxxx", "xxxx", "1000"]);
+        assert_eq!(output("out_data[1]"), ["xxxx", "xxxx", "0110"]);
+        assert_eq!(output("out_valid"), ["xxxx", "0000", "1111"]);
+        // `out_ready || !v` is known even when `v` is not
+        assert_eq!(output("in_ready"), ["1111", "1111", "0000"]);
+        assert_eq!(trace.registers.len(), circuit.registers.len());
+
+        assert!(sim.step(&cycle("0", "0", "0")).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,68 @@
+use std::collections::BTreeMap;
+use std::fmt::Display;
+
+use super::{Circuit, NodeType};
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Stats {
+    pub num_nodes: usize,
+    pub num_edges: usize,
+    pub num_inputs: usize,
+    pub num_outputs: usize,
+    pub num_registers: usize,
+    pub num_secure: usize,
+    /// fresh random bits per cycle
+    pub num_random: usize,
+    /// cycles by which the outputs lag behind the unmasked circuit
+    pub latency: usize,
+    /// number of nodes per node type (e.g. `AND`, `XNOR`, `FF`)
+    pub node_types: BTreeMap<String, usize>,
+}
+
+impl Circuit {
+    pub fn stats(&self) -> Stats {
+        let mut node_types = BTreeMap::new();
+        let mut num_secure = 0;
+        for node in self.graph.node_weights() {
+            if node.secure {
+                num_secure += 1;
+            }
+            if let NodeType::Gate(..)
+            | NodeType::Gadget { .. }
+            | NodeType::Register
+            | NodeType::Blackbox(_) = node.node_type
+            {
+                *node_types.entry(node.node_type.to_string()).or_insert(0) += 1;
+            }
+        }
+        Stats {
+            num_nodes: self.graph.node_count(),
+            num_edges: self.graph.edge_count(),
+            num_inputs: self.inputs.len(),
+            num_outputs: self.outputs.len(),
+            num_registers: self.registers.len(),
+            num_secure,
+            num_random: self.random_inputs().len(),
+            latency: self.latency,
+            node_types,
+        }
+    }
+}
+
+impl Display for Stats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "nodes:     {}", self.num_nodes)?;
+        writeln!(f, "edges:     {}", self.num_edges)?;
+        writeln!(f, "inputs:    {}", self.num_inputs)?;
+        writeln!(f, "outputs:   {}", self.num_outputs)?;
+        writeln!(f, "registers: {}", self.num_registers)?;
+        writeln!(f, "secure:    {}", self.num_secure)?;
+        writeln!(f, "random:    {}", self.num_random)?;
+        writeln!(f, "latency:   {}", self.latency)?;
+        writeln!(f, "cells:")?;
+        for (node_type, count) in self.node_types.iter() {
+            writeln!(f, "  {:<16} {}", node_type, count)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,457 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fmt::Display;
+use std::str::FromStr;
+
+use itertools::Itertools;
+use petgraph::visit::EdgeRef;
+use petgraph::Direction;
+use simple_error::SimpleError;
+
+use super::node::{GateType, NodePortId, NodeType};
+use super::{Circuit, Error, NodeIndex};
+
+/// Why a node carries a secret
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Taint {
+    /// a secure input, or a net inside the circuit classified as secret
+    Source,
+    /// the secret arrives on input `port`, from `driver`
+    Input { port: NodePortId, driver: NodeIndex },
+}
+
+/// secret of a plain MASQ "secure" attribute
+const DEFAULT_SECRET: &str = "secure";
+
+/// Security label: the secrets which a value depends on, public if there are none. Labels are
+/// ordered by inclusion and the label of a node is the join (union) of the labels reaching it.
+/// As MASQ attribute: `public`, `secure` or `secure:key`, with several secrets `secure:key+iv`.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct Label(BTreeSet<String>);
+
+impl Label {
+    pub fn public() -> Self {
+        Self::default()
+    }
+
+    pub fn secret(name: &str) -> Self {
+        Self(BTreeSet::from([name.to_owned()]))
+    }
+
+    pub fn is_public(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn secrets(&self) -> impl Iterator<Item = &str> {
+        self.0.iter().map(String::as_str)
+    }
+
+    /// Add the secrets of `other`, returns whether the label changed
+    pub fn join(&mut self, other: &Label) -> bool {
+        let len = self.0.len();
+        self.0.extend(other.0.iter().cloned());
+        self.0.len() != len
+    }
+}
+
+impl FromStr for Label {
+    type Err = SimpleError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            None if s == "public" => Ok(Self::public()),
+            None if s == DEFAULT_SECRET => Ok(Self::secret(DEFAULT_SECRET)),
+            Some((DEFAULT_SECRET, secrets)) if !secrets.is_empty() => {
+                Ok(Self(secrets.split('+').map(str::to_owned).collect()))
+            }
+            _ => Err(SimpleError::new(format!("invalid security label {}", s))),
+        }
+    }
+}
+
+impl Display for Label {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.is_public() {
+            f.write_str("public")
+        } else if *self == Self::secret(DEFAULT_SECRET) {
+            f.write_str(DEFAULT_SECRET)
+        } else {
+            write!(f, "{}:{}", DEFAULT_SECRET, self.secrets().join("+"))
+        }
+    }
+}
+
+/// Which nodes a secret reaches
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SecretReach {
+    pub num_nodes: usize,
+    pub num_registers: usize,
+    /// names of the outputs
+    pub outputs: Vec<String>,
+}
+
+/// secret -> the nodes it reaches
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SecretReport(pub BTreeMap<String, SecretReach>);
+
+impl Display for SecretReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (secret, reach) in self.0.iter() {
+            write!(
+                f,
+                "{}: {} nodes, {} registers",
+                secret, reach.num_nodes, reach.num_registers
+            )?;
+            if !reach.outputs.is_empty() {
+                write!(f, ", outputs {}", reach.outputs.join(" "))?;
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
+
+impl Circuit {
+    /// Label of an input, from its MASQ attribute. A secure input without one has the default
+    /// secret.
+    pub fn input_label(&self, nx: NodeIndex) -> Label {
+        match self.labels.get(&nx) {
+            Some(label) => label.clone(),
+            None if self.graph[nx].secure => Label::secret(DEFAULT_SECRET),
+            None => Label::public(),
+        }
+    }
+
+    /// Set the label of an input, which is secure unless the label is public
+    pub fn set_input_label(&mut self, nx: NodeIndex, label: Label) {
+        self.graph[nx].secure = !label.is_public();
+        self.labels.insert(nx, label);
+    }
+
+    /// Label of a node, once propagated by [Circuit::propagate_secure]
+    pub fn label(&self, nx: NodeIndex) -> Label {
+        self.labels.get(&nx).cloned().unwrap_or_default()
+    }
+
+    /// Only mask these secrets, other secure inputs are left public
+    pub fn select_secrets<S: Into<String>>(&mut self, secrets: impl IntoIterator<Item = S>) {
+        self.masked_secrets = Some(secrets.into_iter().map(Into::into).collect());
+    }
+
+    /// Whether a node with `label` is masked
+    pub(crate) fn is_masked(&self, label: &Label) -> bool {
+        match &self.masked_secrets {
+            _ if label.is_public() => false,
+            None => true,
+            Some(selected) => label.secrets().any(|s| selected.contains(s)),
+        }
+    }
+
+    /// inputs with a secret and the classified nets inside the circuit, and their labels
+    fn secret_sources(&self) -> Vec<(NodeIndex, Label)> {
+        self.inputs
+            .iter()
+            .map(|&nx| (nx, self.input_label(nx)))
+            .chain(
+                self.classified
+                    .iter()
+                    .map(|(&nx, label)| (nx, label.clone())),
+            )
+            .filter(|(_, label)| !label.is_public())
+            .collect()
+    }
+
+    /// value of the constant driving input `port` of `nx`, if any
+    fn constant_input(&self, nx: NodeIndex, port: NodePortId) -> Option<bool> {
+        self.graph
+            .edges_directed(nx, Direction::Incoming)
+            .filter(|e| e.weight().1 == port)
+            .find_map(|e| match self.graph[e.source()].node_type {
+                NodeType::Constant(v) => Some(v),
+                _ => None,
+            })
+    }
+
+    fn has_constant_input(&self, nx: NodeIndex, value: bool) -> bool {
+        self.graph
+            .neighbors_directed(nx, Direction::Incoming)
+            .any(|sx| self.graph[sx].node_type == NodeType::Constant(value))
+    }
+
+    /// Whether a secret on input `port` of `nx` reaches its output. Clocks and the random bits
+    /// of gadgets do not, nor do the inputs of gates whose output a constant input fixes, e.g.
+    /// an AND with a 0 input or the unselected input of a MUX with a constant select.
+    fn propagates(&self, nx: NodeIndex, port: NodePortId) -> bool {
+        let node_type = &self.graph[nx].node_type;
+        match node_type {
+            NodeType::Register => port == 1,
+            NodeType::Gadget { num_shares, .. } => node_type
+                .num_gadget_operands()
+                .is_some_and(|n| (port as usize) < *num_shares as usize * n),
+            NodeType::Gate(GateType::And(_), _) => !self.has_constant_input(nx, false),
+            NodeType::Gate(GateType::Or(_), _) => !self.has_constant_input(nx, true),
+            NodeType::Gate(GateType::Mux, _) => match self.constant_input(nx, 0) {
+                Some(select) => port == 1 + u8::from(select),
+                None => true,
+            },
+            NodeType::Gate(GateType::Buf | GateType::Xor(_), _)
+            | NodeType::Blackbox(_)
+            | NodeType::Output => true,
+            NodeType::Input
+            | NodeType::Control
+            | NodeType::Random
+            | NodeType::Clock
+            | NodeType::Reset
+            | NodeType::Constant(_) => false,
+        }
+    }
+
+    /// Labels of the nodes which carry a secret: the join of the labels of the secret sources
+    /// reaching them over inputs which propagate them, see [Circuit::propagates], iterated
+    /// through register loops to a fixed point. The labels stop at the declassification points.
+    pub fn propagate_labels(&self) -> HashMap<NodeIndex, Label> {
+        let mut labels: HashMap<NodeIndex, Label> = self.secret_sources().into_iter().collect();
+        let mut worklist = labels.keys().copied().collect_vec();
+        while let Some(nx) = worklist.pop() {
+            if self.declassified.contains(&nx) {
+                continue;
+            }
+            let label = labels[&nx].clone();
+            for e in self.graph.edges_directed(nx, Direction::Outgoing) {
+                let (dx, port) = (e.target(), e.weight().1);
+                if self.propagates(dx, port) && labels.entry(dx).or_default().join(&label) {
+                    worklist.push(dx);
+                }
+            }
+        }
+        labels
+    }
+
+    /// The nodes reached by each secret, once propagated by [Circuit::propagate_secure]
+    pub fn secret_report(&self) -> SecretReport {
+        let mut report = SecretReport::default();
+        for (&nx, label) in self.labels.iter() {
+            let node = &self.graph[nx];
+            for secret in label.secrets() {
+                let reach = report.0.entry(secret.to_owned()).or_default();
+                reach.num_nodes += 1;
+                match node.node_type {
+                    NodeType::Register => reach.num_registers += 1,
+                    NodeType::Output => reach.outputs.extend(node.name.clone()),
+                    _ => {}
+                }
+            }
+        }
+        for reach in report.0.values_mut() {
+            reach.outputs.sort();
+        }
+        report
+    }
+
+    /// Nodes which carry a secret, each with the reason for its taint, which is the first
+    /// input found to carry a secret, see [Circuit::propagate_labels].
+    pub fn taint(&self) -> HashMap<NodeIndex, Taint> {
+        let sources = self
+            .secret_sources()
+            .into_iter()
+            .map(|(nx, _)| nx)
+            .collect_vec();
+        let mut taint: HashMap<NodeIndex, Taint> =
+            sources.iter().map(|&nx| (nx, Taint::Source)).collect();
+        let mut worklist = sources;
+        while let Some(nx) = worklist.pop() {
+            if self.declassified.contains(&nx) {
+                continue;
+            }
+            for e in self.graph.edges_directed(nx, Direction::Outgoing) {
+                let (dx, port) = (e.target(), e.weight().1);
+                if !taint.contains_key(&dx) && self.propagates(dx, port) {
+                    taint.insert(dx, Taint::Input { port, driver: nx });
+                    worklist.push(dx);
+                }
+            }
+        }
+        taint
+    }
+
+    /// Path by which the secret reaches `nx`, from a secure input to `nx`, or `None` if `nx`
+    /// is not tainted
+    pub fn taint_path(taint: &HashMap<NodeIndex, Taint>, nx: NodeIndex) -> Option<Vec<NodeIndex>> {
+        let mut path = vec![nx];
+        loop {
+            match taint.get(path.last()?)? {
+                Taint::Source => break,
+                Taint::Input { driver, .. } => path.push(*driver),
+            }
+        }
+        path.reverse();
+        Some(path)
+    }
+
+    /// Why the node called `name` carries a secret, as the path from a secure input with the
+    /// input port of each step, e.g. `in_data[0] -> XOR_12.1 -> out_data[0].0`
+    pub fn explain_taint(&self, name: &str) -> Result<String, Error> {
+        let nx = self
+            .graph
+            .node_indices()
+            .find(|&nx| self.graph[nx].name.as_deref() == Some(name))
+            .ok_or_else(|| SimpleError::new(format!("no node called {}", name)))?;
+        let taint = self.taint();
+        let Some(path) = Self::taint_path(&taint, nx) else {
+            return Ok(format!("{} is public", name));
+        };
+        Ok(path
+            .into_iter()
+            .map(|nx| {
+                let node = &self.graph[nx];
+                let name = node
+                    .name
+                    .clone()
+                    .unwrap_or_else(|| format!("{}_{}", node.node_type, nx.index()));
+                match taint[&nx] {
+                    Taint::Source => name,
+                    Taint::Input { port, .. } => format!("{}.{}", name, port),
+                }
+            })
+            .join(" -> "))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    use super::*;
+    use crate::circuit::{GadgetKind, Masking};
+
+    #[test]
+    fn port_roles() {
+        let mut circuit = Circuit::default();
+        let a = circuit.add_test_node(true, NodeType::Input, "a");
+        let b = circuit.add_test_node(false, NodeType::Input, "b");
+        let zero = circuit.add_test_node(false, NodeType::Constant(false), "zero");
+        let masked_and =
+            circuit.add_test_node(false, NodeType::Gate(GateType::And(2), false), "masked_and");
+        let mux = circuit.add_test_node(false, NodeType::Gate(GateType::Mux, false), "mux");
+        let clocked = circuit.add_test_node(false, NodeType::Register, "clocked");
+        let xor = circuit.add_test_node(false, NodeType::Gate(GateType::Xor(2), false), "xor");
+        let acc = circuit.add_test_node(false, NodeType::Register, "acc");
+        // a & 0, 0 ? b : a, and a register clocked by a
+        circuit.connect(a, 0, masked_and, 0);
+        circuit.connect(zero, 0, masked_and, 1);
+        circuit.connect(zero, 0, mux, 0);
+        circuit.connect(b, 0, mux, 1);
+        circuit.connect(a, 0, mux, 2);
+        circuit.connect(a, 0, clocked, 0);
+        circuit.connect(b, 0, clocked, 1);
+        // acc <= acc ^ a
+        circuit.connect(acc, 0, xor, 0);
+        circuit.connect(a, 0, xor, 1);
+        circuit.connect(b, 0, acc, 0);
+        circuit.connect(xor, 0, acc, 1);
+
+        let taint = circuit.taint();
+        for nx in [masked_and, mux, clocked, b] {
+            assert!(!taint.contains_key(&nx), "{:?}", circuit.graph[nx].name);
+        }
+        assert_eq!(taint[&a], Taint::Source);
+        assert_eq!(taint[&xor], Taint::Input { port: 1, driver: a });
+        assert_eq!(Circuit::taint_path(&taint, acc), Some(vec![a, xor, acc]));
+        assert_eq!(Circuit::taint_path(&taint, mux), None);
+        assert_eq!(circuit.explain_taint("acc").unwrap(), "a -> xor.1 -> acc.1");
+        assert_eq!(circuit.explain_taint("mux").unwrap(), "mux is public");
+        assert!(circuit.explain_taint("c").is_err());
+    }
+
+    /// `y = k0 & k1`, `z = s0 & s1` and `w = y ^ s0`, with a key `k` and a state `s`
+    fn key_and_state() -> Circuit {
+        let mut circuit = Circuit::default();
+        let [k0, k1, s0, s1] = ["k0", "k1", "s0", "s1"]
+            .map(|name| circuit.add_test_node(false, NodeType::Input, name));
+        let [y, z, w] =
+            ["y", "z", "w"].map(|name| circuit.add_test_node(false, NodeType::Output, name));
+        let and_k = circuit.add_test_node(false, NodeType::Gate(GateType::And(2), false), "and_k");
+        let and_s = circuit.add_test_node(false, NodeType::Gate(GateType::And(2), false), "");
+        let xor = circuit.add_test_node(false, NodeType::Gate(GateType::Xor(2), false), "xor");
+        for (sx, dx, port) in [
+            (k0, and_k, 0),
+            (k1, and_k, 1),
+            (s0, and_s, 0),
+            (s1, and_s, 1),
+            (and_k, xor, 0),
+            (s0, xor, 1),
+            (and_k, y, 0),
+            (and_s, z, 0),
+            (xor, w, 0),
+        ] {
+            circuit.connect(sx, 0, dx, port);
+        }
+        for (inputs, label) in [([k0, k1], "secure:key"), ([s0, s1], "secure:state")] {
+            for nx in inputs {
+                circuit.set_input_label(nx, label.parse().unwrap());
+            }
+        }
+        circuit
+    }
+
+    #[test]
+    fn labels() -> Result<(), crate::circuit::Error> {
+        for label in ["public", "secure", "secure:key", "secure:iv+key"] {
+            assert_eq!(label.parse::<Label>()?.to_string(), label);
+        }
+        assert!("secure:".parse::<Label>().is_err());
+        assert!("secret".parse::<Label>().is_err());
+
+        let circuit = key_and_state();
+        let mut labeled = circuit.clone();
+        labeled.propagate_secure();
+        let node = |name: &str| {
+            labeled
+                .graph
+                .node_indices()
+                .find(|&nx| labeled.graph[nx].name.as_deref() == Some(name))
+                .unwrap()
+        };
+        assert_eq!(labeled.label(node("and_k")), Label::secret("key"));
+        assert_eq!(labeled.label(node("xor")).to_string(), "secure:key+state");
+        let report = labeled.secret_report();
+        assert_eq!(report.0["key"].outputs, ["w", "y"]);
+        assert_eq!(report.0["state"].outputs, ["w", "z"]);
+
+        // each secret has its own randomness, which is not shared with the other
+        let mut masked = circuit.clone();
+        masked.mask_with(2, GadgetKind::Isw)?;
+        let names = masked
+            .random_inputs()
+            .into_iter()
+            .filter_map(|rx| masked.graph[rx].name.clone())
+            .sorted()
+            .collect_vec();
+        assert_eq!(names.len(), 6);
+        assert_eq!(names[0], "rand_key[0]");
+        assert_eq!(names[3], "rand_state[0]");
+        // nor when reusing randomness, which is only possible at order 1
+        assert!(masked.reuse_randomness().is_err());
+        let mut masked = circuit.clone();
+        masked.mask_with(1, GadgetKind::Isw)?;
+        assert_eq!(masked.reuse_randomness()?.saved(), 0);
+
+        // only mask the key
+        let mut masked = circuit.clone();
+        masked.select_secrets(["key"]);
+        masked.mask(1)?;
+        let num_gadgets = masked
+            .graph
+            .node_weights()
+            .filter(|node| matches!(node.node_type, NodeType::Gadget { .. }))
+            .count();
+        assert_eq!(num_gadgets, 1);
+        assert!(masked
+            .inputs
+            .iter()
+            .any(|&nx| masked.graph[nx].name.as_deref() == Some("s0")));
+        let mut rng = StdRng::seed_from_u64(0);
+        assert_eq!(circuit.check_equivalence(&masked, 4, 64, &mut rng)?, None);
+        Ok(())
+    }
+}
@@ -0,0 +1,34 @@
+//! Fixtures shared by the tests of the circuit modules
+
+use super::node::{NodeBuilder, NodeType};
+use super::{Circuit, NetlistAndLibrary, NodeIndex};
+
+/// The circuit of `tests/hdl/simple/simple_1.json`, registered AND and XOR
+/// of two bits with a valid/ready handshake
+pub(super) fn simple_1() -> Circuit {
+    let netlist_path = concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/",
+        "tests/hdl/simple/simple_1.json"
+    );
+    let netlist = NetlistAndLibrary::from_path(netlist_path).unwrap();
+    Circuit::try_from(&netlist).unwrap()
+}
+
+impl Circuit {
+    /// Add a node to a circuit built by a test, unnamed if `name` is empty
+    pub(super) fn add_test_node(
+        &mut self,
+        secure: bool,
+        node_type: NodeType,
+        name: &str,
+    ) -> NodeIndex {
+        let node = NodeBuilder::default()
+            .secure(secure)
+            .node_type(node_type)
+            .name((!name.is_empty()).then(|| name.to_owned()))
+            .build()
+            .unwrap();
+        self.add_node(node)
+    }
+}
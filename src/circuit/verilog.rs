@@ -0,0 +1,76 @@
+use std::fs::File;
+use std::io::{BufWriter, Write};
+
+use crate::netlist::json_netlist::Netlist;
+
+use super::{Circuit, Error, NodeType};
+
+pub trait Verilog {
+    /// Write the circuit as a structural Verilog module instantiating library cells.
+    /// Gadgets are written as the library cells of their gates and registers, expanded in a
+    /// copy of the circuit, see [`Circuit::expand_gadgets`].
+    fn write_verilog<W: Write>(&self, w: &mut W) -> Result<(), Error>;
+
+    fn write_verilog_file(&self, outfile: &str) -> Result<(), Error> {
+        let mut w = BufWriter::new(File::create(outfile)?);
+        self.write_verilog(&mut w)?;
+        w.flush()?;
+        Ok(())
+    }
+}
+
+impl Verilog for Circuit {
+    fn write_verilog<W: Write>(&self, w: &mut W) -> Result<(), Error> {
+        let has_gadgets = self
+            .graph
+            .node_weights()
+            .any(|node| matches!(node.node_type, NodeType::Gadget { .. }));
+        let netlist = if has_gadgets {
+            let mut expanded = self.clone();
+            expanded.expand_gadgets();
+            Netlist::try_from(&expanded)?
+        } else {
+            Netlist::try_from(self)?
+        };
+        writeln!(w, "/* Generated by {} */\n", netlist.creator)?;
+        netlist.modules[&self.name].write_verilog(&self.name, w)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::circuit::test_utils::simple_1;
+    use crate::circuit::Masking;
+
+    #[test]
+    fn write_masked_simple() -> Result<(), Error> {
+        let mut circuit = simple_1();
+        circuit.mask(1)?;
+
+        let mut out = Vec::new();
+        circuit.write_verilog(&mut out)?;
+        let verilog = String::from_utf8(out).unwrap();
+
+        assert!(verilog.contains("module simple_1("));
+        assert!(verilog.contains("  input [1:0] in_data_s0;"));
+        assert!(verilog.contains("  input [1:0] in_data_s1;"));
+        assert!(verilog.contains("  output [1:0] out_data_s1;"));
+        assert!(verilog.contains("  (* MASQ = \"clock\" *)\n  input clk;"));
+        // the gadget is written as its gates and registers
+        assert!(!verilog.contains("masq_and_gadget_2") && !verilog.contains("blackbox"));
+        assert_eq!(verilog.matches("module ").count(), 1);
+        assert_eq!(verilog.matches("endmodule").count(), 1);
+        // the 4 products of the shares, of which the 2 across the shares are masked by `rand`
+        for (a, b) in [(0, 0), (0, 1), (1, 0), (1, 1)] {
+            let product = format!("(.A(in_data_s{}[0]), .B(in_data_s{}[1]), .Y(", a, b);
+            let ands = verilog
+                .lines()
+                .filter(|l| l.starts_with("  AND ") && l.contains(&product));
+            assert_eq!(ands.count(), 1, "{}", product);
+        }
+        assert_eq!(verilog.matches(", .B(rand), .Y(").count(), 2);
+        Ok(())
+    }
+}
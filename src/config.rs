@@ -0,0 +1,264 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use itertools::Itertools;
+use regex::Regex;
+use serde_derive::Deserialize;
+
+use crate::netlist::json_netlist::{AttributeVal, Netlist};
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error(transparent)]
+    IoError(#[from] std::io::Error),
+    #[error(transparent)]
+    TomlError(#[from] toml::de::Error),
+    #[error("{0} not found in the netlist")]
+    NotFound(String),
+    #[error("no netlist in [flow.yosys]")]
+    MissingNetlist,
+}
+
+/// Project configuration, describing a whole masking job
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct Config {
+    pub name: Option<String>,
+    #[serde(default)]
+    pub rtl: Rtl,
+    #[serde(default)]
+    pub flow: Flow,
+    /// directory of the config file, which relative paths are based on
+    #[serde(skip)]
+    pub base_dir: PathBuf,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct Rtl {
+    #[serde(default)]
+    pub sources: Vec<PathBuf>,
+    pub top: Option<String>,
+    pub clock_port: Option<String>,
+    /// attribute name -> `module/net` -> attribute value, e.g. `[rtl.attributes.MASQ]`.
+    /// The net may be a pattern with `*` and `?` wildcards, e.g. `"key_reg*"`.
+    #[serde(default)]
+    pub attributes: HashMap<String, HashMap<String, String>>,
+    /// secret -> input nets delivering its shares, in order, e.g. `key = ["key_a", "key_b"]`.
+    /// Sets the MASQ attributes of the nets to `share:0:key`, `share:1:key`... after the other
+    /// attributes.
+    #[serde(default)]
+    pub shares: HashMap<String, Vec<String>>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct Flow {
+    #[serde(default)]
+    pub yosys: Yosys,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct Yosys {
+    /// Liberty cell library used for synthesis
+    pub liberty: Option<PathBuf>,
+    /// structural Verilog netlist
+    pub netlist: Option<PathBuf>,
+    /// Yosys JSON netlist
+    pub netlist_json: Option<PathBuf>,
+}
+
+impl Config {
+    pub fn from_toml_str(src: &str, base_dir: &Path) -> Result<Self, Error> {
+        let mut config: Config = toml::from_str(src)?;
+        config.base_dir = base_dir.to_owned();
+        Ok(config)
+    }
+
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        let src = std::fs::read_to_string(&path)?;
+        let base_dir = path.as_ref().parent().unwrap_or_else(|| Path::new(""));
+        Self::from_toml_str(&src, base_dir)
+    }
+
+    fn path(&self, path: &Path) -> PathBuf {
+        self.base_dir.join(path)
+    }
+
+    pub fn liberty_path(&self) -> Option<PathBuf> {
+        self.flow.yosys.liberty.as_ref().map(|p| self.path(p))
+    }
+
+    /// Netlist to read, preferring the Yosys JSON netlist
+    pub fn netlist_path(&self) -> Result<PathBuf, Error> {
+        let yosys = &self.flow.yosys;
+        yosys
+            .netlist_json
+            .as_ref()
+            .or(yosys.netlist.as_ref())
+            .map(|p| self.path(p))
+            .ok_or(Error::MissingNetlist)
+    }
+
+    /// Mark the configured top module as top and add the configured attributes to the nets, or
+    /// to the cells if there is no such net.
+    /// Nets are named `module/net`, or just `net` for nets of the top module. A pattern applies
+    /// to all the nets and cells it matches, exact names take precedence over patterns.
+    /// The inputs of [Rtl::shares] get their MASQ attributes last.
+    pub fn apply_attributes(&self, netlist: &mut Netlist) -> Result<(), Error> {
+        if let Some(top) = &self.rtl.top {
+            if !netlist.modules.contains_key(top) {
+                return Err(Error::NotFound(format!("top module {}", top)));
+            }
+            for (name, module) in netlist.modules.iter_mut() {
+                if name == top {
+                    module
+                        .attributes
+                        .insert("top".to_owned(), AttributeVal::Number(1));
+                } else {
+                    module.attributes.remove("top");
+                }
+            }
+        }
+        let top = netlist.get_top().map(|(name, _)| name.clone());
+        let values = self.rtl.attributes.iter().flat_map(|(attribute, values)| {
+            values
+                .iter()
+                .sorted_by_key(|(target, _)| !is_pattern(target))
+                .map(move |(target, value)| (attribute.clone(), target, value.clone()))
+        });
+        let shares = self.rtl.shares.iter().flat_map(|(secret, nets)| {
+            nets.iter().enumerate().map(move |(share, target)| {
+                let value = format!("share:{}:{}", share, secret);
+                ("MASQ".to_owned(), target, value)
+            })
+        });
+        for (attribute, target, value) in values.chain(shares) {
+            let (module_name, net_name) = match target.split_once('/') {
+                Some((module, net)) => (Some(module.to_owned()), net),
+                None => (top.clone(), target.as_str()),
+            };
+            let not_found = || Error::NotFound(format!("net or cell {}", target));
+            let module = module_name
+                .and_then(|m| netlist.modules.get_mut(&m))
+                .ok_or_else(not_found)?;
+            let names = if is_pattern(net_name) {
+                let regex = pattern_regex(net_name);
+                module
+                    .netnames
+                    .keys()
+                    .chain(module.cells.keys())
+                    .filter(|name| regex.is_match(name))
+                    .unique()
+                    .cloned()
+                    .collect_vec()
+            } else {
+                vec![net_name.to_owned()]
+            };
+            if names.is_empty() {
+                return Err(not_found());
+            }
+            for name in names {
+                let attributes = match module.netnames.get_mut(&name) {
+                    Some(net) => &mut net.attributes,
+                    None => module
+                        .cells
+                        .get_mut(&name)
+                        .map(|cell| &mut cell.attributes)
+                        .ok_or_else(not_found)?,
+                };
+                attributes.insert(attribute.clone(), AttributeVal::String(value.clone()));
+            }
+        }
+        Ok(())
+    }
+}
+
+fn is_pattern(name: &str) -> bool {
+    name.contains(['*', '?'])
+}
+
+/// regex matching whole names against a pattern with `*` and `?` wildcards
+fn pattern_regex(pattern: &str) -> Regex {
+    let regex = regex::escape(pattern)
+        .replace(r"\*", ".*")
+        .replace(r"\?", ".");
+    Regex::new(&format!("^{}$", regex)).expect("escaped pattern is a valid regex")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn apply_simple_1() -> Result<(), Box<dyn std::error::Error>> {
+        let base_dir = Path::new(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/hdl/simple"));
+        let config = Config::from_path(base_dir.join("simple_1.toml"))?;
+        assert_eq!(config.rtl.top.as_deref(), Some("simple_1"));
+        assert_eq!(config.netlist_path()?, base_dir.join("simple_1.json"));
+        assert!(config.liberty_path().unwrap().exists());
+
+        let mut netlist = Netlist::from_reader(std::fs::File::open(config.netlist_path()?)?)?;
+        for module in netlist.modules.values_mut() {
+            for net in module.netnames.values_mut() {
+                net.attributes.clear();
+            }
+        }
+        config.apply_attributes(&mut netlist)?;
+        let (top_name, top) = netlist.get_top().unwrap();
+        assert_eq!(top_name, "simple_1");
+        let masq = |net: &str| top.netnames[net].attributes.get("MASQ").cloned();
+        assert_eq!(masq("in_data"), Some(AttributeVal::String("secure".into())));
+        assert_eq!(masq("clk"), Some(AttributeVal::String("clock".into())));
+        assert_eq!(
+            masq("in_valid"),
+            Some(AttributeVal::String("constant".into()))
+        );
+        assert_eq!(masq("out_data"), None);
+        Ok(())
+    }
+
+    #[test]
+    fn apply_patterns() -> Result<(), Box<dyn std::error::Error>> {
+        let base_dir = Path::new(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/hdl/simple"));
+        let mut config = Config::from_path(base_dir.join("simple_1.toml"))?;
+        let masq = config.rtl.attributes.get_mut("MASQ").unwrap();
+        masq.insert("*new_n2?_".into(), "secure:key".into());
+        masq.insert("$abc$127$new_n21_".into(), "public".into());
+
+        let mut netlist = Netlist::from_reader(std::fs::File::open(config.netlist_path()?)?)?;
+        config.apply_attributes(&mut netlist)?;
+        let (_, top) = netlist.get_top().unwrap();
+        let masq = |net: &str| top.netnames[net].attributes.get("MASQ").cloned();
+        let key = Some(AttributeVal::String("secure:key".into()));
+        assert_eq!(masq("$abc$127$new_n20_"), key);
+        assert_eq!(
+            masq("$abc$127$new_n21_"),
+            Some(AttributeVal::String("public".into()))
+        );
+        assert_eq!(masq("$abc$127$new_n19_"), None);
+
+        config.rtl.shares.insert(
+            "data".into(),
+            vec!["in_data".into(), "simple_1/in_valid".into()],
+        );
+        config.apply_attributes(&mut netlist)?;
+        let (_, top) = netlist.get_top().unwrap();
+        let masq = |net: &str| top.netnames[net].attributes.get("MASQ").cloned();
+        assert_eq!(
+            masq("in_data"),
+            Some(AttributeVal::String("share:0:data".into()))
+        );
+        assert_eq!(
+            masq("in_valid"),
+            Some(AttributeVal::String("share:1:data".into()))
+        );
+
+        config.rtl.attributes.insert(
+            "MASQ".into(),
+            HashMap::from([("key*".into(), "secure".into())]),
+        );
+        assert!(matches!(
+            config.apply_attributes(&mut netlist),
+            Err(Error::NotFound(_))
+        ));
+        Ok(())
+    }
+}
@@ -1,4 +1,5 @@
 pub mod circuit;
+pub mod config;
 pub mod netlist;
 pub mod utils;
 pub mod logic;
\ No newline at end of file
@@ -48,6 +48,16 @@ impl TryFrom<&Logic> for bool {
     }
 }
 
+impl From<bool> for Logic {
+    fn from(value: bool) -> Self {
+        if value {
+            Logic::One
+        } else {
+            Logic::Zero
+        }
+    }
+}
+
 impl TryFrom<char> for Logic {
     type Error = Error;
 
@@ -116,8 +126,84 @@ impl LogicVec {
         // for (i, j) in zx.zip(v01) {}
     }
 
+    /// `len` copies of `value`
+    pub fn repeat(value: Logic, len: usize) -> Self {
+        let v = value as u8;
+        Self {
+            bv_zx: BitVec::repeat(v & 2 != 0, len),
+            bv_01: BitVec::repeat(v & 1 != 0, len),
+        }
+    }
+
+    /// Apply `f` to whole words of the `operands`, which all have the same length.
+    /// `f` maps the (zeros, ones) masks of each operand to the (zeros, ones) mask of the result.
+    /// Bits of the result which are neither 0 nor 1 are X.
+    fn map_words<const N: usize, F>(operands: [&LogicVec; N], f: F) -> Self
+    where
+        F: Fn([(usize, usize); N]) -> (usize, usize),
+    {
+        let len = operands.first().map_or(0, |op| op.len());
+        assert!(
+            operands.iter().all(|op| op.len() == len),
+            "operands of different lengths"
+        );
+        let num_words = operands.first().map_or(0, |op| op.bv_zx.as_raw_slice().len());
+        let mut bv_zx = Vec::with_capacity(num_words);
+        let mut bv_01 = Vec::with_capacity(num_words);
+        for w in 0..num_words {
+            let (zeros, ones) = f(operands.map(|op| {
+                let zx = op.bv_zx.as_raw_slice()[w];
+                let v01 = op.bv_01.as_raw_slice()[w];
+                (zx & !v01, zx & v01)
+            }));
+            let known = zeros | ones;
+            bv_zx.push(known);
+            bv_01.push(ones | !known);
+        }
+        let mut lv = Self {
+            bv_zx: BitVec::from_vec(bv_zx),
+            bv_01: BitVec::from_vec(bv_01),
+        };
+        lv.resize(len);
+        lv
+    }
+
+    /// Z inputs are read as X
+    pub fn buf(&self) -> Self {
+        Self::map_words([self], |[a]| a)
+    }
+
+    pub fn not(&self) -> Self {
+        Self::map_words([self], |[(zeros, ones)]| (ones, zeros))
+    }
+
+    pub fn and(&self, other: &Self) -> Self {
+        Self::map_words([self, other], |[(z0, o0), (z1, o1)]| (z0 | z1, o0 & o1))
+    }
+
+    pub fn or(&self, other: &Self) -> Self {
+        Self::map_words([self, other], |[(z0, o0), (z1, o1)]| (z0 & z1, o0 | o1))
+    }
+
+    pub fn xor(&self, other: &Self) -> Self {
+        Self::map_words([self, other], |[(z0, o0), (z1, o1)]| {
+            ((z0 & z1) | (o0 & o1), (z0 & o1) | (o0 & z1))
+        })
+    }
+
+    /// `b` where `self` is 1 and `a` where it is 0.
+    /// Where `self` is unknown, the result is only known if `a` and `b` agree.
+    pub fn mux(&self, a: &Self, b: &Self) -> Self {
+        Self::map_words([self, a, b], |[(zs, os), (za, oa), (zb, ob)]| {
+            (
+                (zs & za) | (os & zb) | (za & zb),
+                (zs & oa) | (os & ob) | (oa & ob),
+            )
+        })
+    }
+
     #[inline(always)]
-    fn iter(&self) -> impl Iterator<Item=Logic> + DoubleEndedIterator<Item=Logic> + '_ {
+    pub fn iter(&self) -> impl DoubleEndedIterator<Item=Logic> + '_ {
         self.bv_zx.iter().zip(self.bv_01.iter()).map(|(v_xz, v_01)|
             Logic::from(v_xz.as_u8() * 2 + v_01.as_u8())
         )
@@ -134,10 +220,13 @@ impl LogicVec {
     }
 }
 
-trait LogicVector {
+pub trait LogicVector {
     fn with_capacity(capacity: usize) -> Self;
     fn capacity(&self) -> usize;
     fn len(&self) -> usize;
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
     fn shrink_to_fit(&mut self);
     fn set(&mut self, index: usize, value: Logic);
     fn get_as<I>(&self, index: usize) -> I where I: Integral + Debug + From<u8>;
@@ -301,6 +390,16 @@ impl<I> From<I> for LogicVec where I: Integral {
     }
 }
 
+impl FromIterator<Logic> for LogicVec {
+    fn from_iter<I: IntoIterator<Item = Logic>>(iter: I) -> Self {
+        let mut lv = Self::default();
+        for value in iter {
+            lv.push(value);
+        }
+        lv
+    }
+}
+
 impl FromStr for LogicVec {
     type Err = Error;
 
@@ -418,4 +517,25 @@ mod tests {
         println!("{}", lv);
         Ok(())
     }
+
+    #[test]
+    fn test_lv2_gates() -> Result<(), Error> {
+        // all combinations of two 4-valued inputs
+        let a = LogicVec::from_str("11110000xxxxzzzz")?;
+        let b = LogicVec::from_str("10xz10xz10xz10xz")?;
+        assert_eq!(a.and(&b).to_string(), "10xx0000x0xxx0xx");
+        assert_eq!(a.or(&b).to_string(), "111110xx1xxx1xxx");
+        assert_eq!(a.xor(&b).to_string(), "01xx10xxxxxxxxxx");
+        assert_eq!(a.not().to_string(), "00001111xxxxxxxx");
+        assert_eq!(b.buf().to_string(), "10xx10xx10xx10xx");
+        let s = LogicVec::from_str("0011xx")?;
+        let a = LogicVec::from_str("010101")?;
+        let b = LogicVec::from_str("101011")?;
+        assert_eq!(s.mux(&a, &b).to_string(), "0110x1");
+        let ones = LogicVec::repeat(Logic::One, 70);
+        let zeros = LogicVec::repeat(Logic::Zero, 70);
+        assert_eq!(ones.xor(&zeros), ones);
+        assert_eq!(ones.and(&LogicVec::repeat(Logic::Z, 70)).to_string(), "x".repeat(70));
+        Ok(())
+    }
 }
\ No newline at end of file
@@ -1,8 +1,20 @@
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+
+use clap::{Args, Parser, Subcommand, ValueEnum};
 use masquerade::circuit;
+use masquerade::config::Config;
+use masquerade::netlist::json_netlist::Netlist;
+
+use crate::circuit::CellLibrary;
+use crate::circuit::Circuit;
 use crate::circuit::Dot;
+use crate::circuit::GadgetKind;
 use crate::circuit::Masking;
 use crate::circuit::NetlistAndLibrary;
-
+use crate::circuit::Verilog;
 
 #[derive(thiserror::Error, Debug)]
 enum AppError {
@@ -12,69 +24,396 @@ enum AppError {
     SerdeError(#[from] serde_json::Error),
     #[error(transparent)]
     CircuitError(#[from] circuit::Error),
+    #[error("the masked circuit is not equivalent to the original:\n{0}")]
+    NotEquivalent(Box<circuit::Mismatch>),
 }
 
-fn main() -> Result<(), AppError> {
-    let netlist_path = concat!(
-        env!("CARGO_MANIFEST_DIR"),
-        "/",
-        "tests/hdl/simple/simple_1.json"
-    );
-    println!("reading netlist: {}", netlist_path);
+#[derive(Parser, Debug)]
+#[command(name = "masquerade", version, about = "Automatic masking of gate-level netlists")]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
 
-    let netlist = NetlistAndLibrary::from_path(netlist_path)?;
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Mask the circuit and write the result
+    Mask {
+        #[command(flatten)]
+        input: InputArgs,
+        /// Masking order (number of shares is `order + 1`, or `2 * order + 1` for ti)
+        #[arg(short, long, default_value_t = 1)]
+        order: u8,
+        /// Gadget scheme of the masked gates: dom-indep, dom-dep, isw, hpc1, hpc2, hpc3 or ti (threshold implementation)
+        #[arg(short, long, default_value_t = GadgetKind::default())]
+        gadget: GadgetKind,
+        /// Share random bits between gadgets with disjoint secure cones which are not recombined,
+        /// keeping the circuit first-order probing secure. Only for masking order 1
+        #[arg(long)]
+        reuse_randomness: bool,
+        /// Only mask this secret, of inputs labeled `secure:SECRET` (default: all secrets)
+        #[arg(long = "secret", value_name = "SECRET")]
+        secrets: Vec<String>,
+        /// Do not insert registers to align the shares and control signals in time, e.g. to
+        /// inspect a circuit which cannot be balanced
+        #[arg(long)]
+        no_balance: bool,
+        /// Replace the gadgets by their gates and registers
+        #[arg(long)]
+        expand: bool,
+        /// Check the masked circuit against the original by simulating this many cycles of random inputs.
+        /// The gadgets are simulated as their gates and registers, also without --expand.
+        #[arg(long, value_name = "CYCLES")]
+        check: Option<usize>,
+        #[command(flatten)]
+        output: OutputArgs,
+    },
+    /// Write the (unmasked) circuit as a GraphViz DOT file
+    Dot {
+        #[command(flatten)]
+        input: InputArgs,
+        /// Output directory
+        #[arg(short = 'O', long, default_value = ".")]
+        output_dir: PathBuf,
+    },
+    /// Print statistics of the circuit, before and optionally after masking
+    Stats {
+        #[command(flatten)]
+        input: InputArgs,
+        /// Also print the statistics of the circuit masked with this order
+        #[arg(short, long)]
+        order: Option<u8>,
+        /// Gadget scheme of the masked gates: dom-indep, dom-dep, isw, hpc1, hpc2, hpc3 or ti (threshold implementation)
+        #[arg(short, long, default_value_t = GadgetKind::default())]
+        gadget: GadgetKind,
+        /// Share random bits between gadgets with disjoint secure cones which are not recombined,
+        /// keeping the circuit first-order probing secure. Only for masking order 1
+        #[arg(long)]
+        reuse_randomness: bool,
+        /// Only mask this secret, of inputs labeled `secure:SECRET` (default: all secrets)
+        #[arg(long = "secret", value_name = "SECRET")]
+        secrets: Vec<String>,
+        /// Do not insert registers to align the shares and control signals in time, e.g. to
+        /// inspect a circuit which cannot be balanced
+        #[arg(long)]
+        no_balance: bool,
+        /// Replace the gadgets by their gates and registers
+        #[arg(long)]
+        expand: bool,
+        /// Explain why the named node carries a secret, by its path from a secure input
+        #[arg(long, value_name = "NODE")]
+        explain: Vec<String>,
+    },
+    /// Convert the netlist to another format without masking
+    Convert {
+        #[command(flatten)]
+        input: InputArgs,
+        #[command(flatten)]
+        output: OutputArgs,
+    },
+}
+
+#[derive(Args, Debug)]
+struct InputArgs {
+    /// Input netlist (Yosys JSON, or structural Verilog with a `.v` extension), or project config (`.toml`)
+    netlist: PathBuf,
+    /// Cell library (Liberty .lib)
+    #[arg(short = 'l', long)]
+    cell_library: Option<PathBuf>,
+}
+
+#[derive(Args, Debug)]
+struct OutputArgs {
+    /// Output format
+    #[arg(short, long, value_enum, default_value_t = OutputFormat::Json)]
+    format: OutputFormat,
+    /// Output directory
+    #[arg(short = 'O', long, default_value = ".")]
+    output_dir: PathBuf,
+}
 
-    println!("Constructing circuit");
-    let mut circuit = circuit::Circuit::try_from(&netlist)?;
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum OutputFormat {
+    /// Yosys JSON netlist
+    Json,
+    /// GraphViz DOT
+    Dot,
+    /// Structural Verilog
+    Verilog,
+}
 
-    let dot_file = format!("{}_orig.dot", circuit.name);
-    println!("Writing DOT to {}", dot_file);
-    circuit.dump_to_file(&dot_file).expect("Writing dot failed");
+impl OutputFormat {
+    fn extension(&self) -> &'static str {
+        match self {
+            OutputFormat::Json => "json",
+            OutputFormat::Dot => "dot",
+            OutputFormat::Verilog => "v",
+        }
+    }
+}
 
-    println!("Propagating secure");
-    circuit.mask(1);
+impl InputArgs {
+    fn load_config(&self) -> Result<NetlistAndLibrary, AppError> {
+        eprintln!("reading config: {}", self.netlist.display());
+        let mut config = Config::from_path(&self.netlist).map_err(circuit::Error::from)?;
+        if let Some(path) = &self.cell_library {
+            // paths in the config are relative to its directory
+            config.flow.yosys.liberty = Some(std::fs::canonicalize(path)?);
+        }
+        Ok(NetlistAndLibrary::from_config(&config)?)
+    }
 
-    let dot_file = format!("{}.dot", circuit.name);
-    println!("Writing DOT to {}", dot_file);
-    circuit.dump_to_file(&dot_file).expect("Writing dot failed");
+    fn load_circuit(&self) -> Result<Circuit, AppError> {
+        let netlist = if self.netlist.extension().is_some_and(|ext| ext == "toml") {
+            self.load_config()?
+        } else {
+            let cell_library = match &self.cell_library {
+                Some(path) => {
+                    eprintln!("reading cell library: {}", path.display());
+                    CellLibrary::from_liberty_path(path).map_err(circuit::Error::from)?
+                }
+                None => CellLibrary::new(),
+            };
+            eprintln!("reading netlist: {}", self.netlist.display());
+            NetlistAndLibrary::from_path_and_library(&self.netlist, cell_library)?
+        };
+        eprintln!("Constructing circuit");
+        Ok(Circuit::try_from(&netlist)?)
+    }
+}
 
-    // circuit.
+fn output_path(output_dir: &Path, name: &str, extension: &str) -> Result<PathBuf, AppError> {
+    std::fs::create_dir_all(output_dir)?;
+    Ok(output_dir.join(format!("{}.{}", name, extension)))
+}
 
+fn write_dot(circuit: &Circuit, path: &Path) -> Result<(), AppError> {
+    eprintln!("Writing DOT to {}", path.display());
+    circuit.dump_to_file(&path.to_string_lossy())?;
     Ok(())
 }
 
+fn write_circuit(circuit: &Circuit, output: &OutputArgs, name: &str) -> Result<(), AppError> {
+    let path = output_path(&output.output_dir, name, output.format.extension())?;
+    match output.format {
+        OutputFormat::Dot => write_dot(circuit, &path),
+        OutputFormat::Json => {
+            eprintln!("Writing JSON netlist to {}", path.display());
+            let netlist = Netlist::try_from(circuit)?;
+            netlist.to_writer(BufWriter::new(File::create(path)?))?;
+            Ok(())
+        }
+        OutputFormat::Verilog => {
+            eprintln!("Writing Verilog netlist to {}", path.display());
+            circuit.write_verilog_file(&path.to_string_lossy())?;
+            Ok(())
+        }
+    }
+}
+
+/// Mask `circuit` and align it in time, unless `no_balance`
+fn mask_circuit(
+    circuit: &mut Circuit,
+    order: u8,
+    gadget: GadgetKind,
+    no_balance: bool,
+) -> Result<(), AppError> {
+    if no_balance {
+        circuit.mask_unbalanced(order, gadget)?;
+    } else {
+        let num_registers = circuit.mask_with(order, gadget)?;
+        println!(
+            "latency of {} cycles, balanced with {} registers",
+            circuit.latency(),
+            num_registers
+        );
+        for name in circuit.unaligned_registers() {
+            eprintln!(
+                "warning: register {} is on a loop through gadgets and could not be aligned, \
+                 the masked circuit is not cycle-equivalent to the original",
+                name
+            );
+        }
+    }
+    Ok(())
+}
+
+fn run(cli: Cli) -> Result<(), AppError> {
+    match cli.command {
+        Command::Mask {
+            input,
+            order,
+            gadget,
+            reuse_randomness,
+            secrets,
+            no_balance,
+            expand,
+            check,
+            output,
+        } => {
+            let mut circuit = input.load_circuit()?;
+            let original = check.map(|_| circuit.clone());
+            if !secrets.is_empty() {
+                circuit.select_secrets(secrets);
+            }
+            eprintln!("Masking with order {} using {} gadgets", order, gadget);
+            mask_circuit(&mut circuit, order, gadget, no_balance)?;
+            if reuse_randomness {
+                println!("Fresh randomness: {}", circuit.reuse_randomness()?);
+            } else {
+                println!(
+                    "Fresh randomness: {} bits per cycle",
+                    circuit.random_inputs().len()
+                );
+            }
+            if expand {
+                circuit.expand_gadgets();
+            }
+            if let (Some(original), Some(num_cycles)) = (original, check) {
+                eprintln!("Checking equivalence for {} cycles", num_cycles);
+                // simulate the gates of the gadgets rather than only their function
+                let mut expanded = circuit.clone();
+                expanded.expand_gadgets();
+                let mismatch = original.check_equivalence(
+                    &expanded,
+                    num_cycles,
+                    64,
+                    &mut rand::thread_rng(),
+                )?;
+                if let Some(mismatch) = mismatch {
+                    return Err(AppError::NotEquivalent(Box::new(mismatch)));
+                }
+            }
+            let name = format!("{}_masked", circuit.name);
+            write_circuit(&circuit, &output, &name)
+        }
+        Command::Dot { input, output_dir } => {
+            let circuit = input.load_circuit()?;
+            let path = output_path(&output_dir, &circuit.name, "dot")?;
+            write_dot(&circuit, &path)
+        }
+        Command::Stats {
+            input,
+            order,
+            gadget,
+            reuse_randomness,
+            secrets,
+            no_balance,
+            expand,
+            explain,
+        } => {
+            let mut circuit = input.load_circuit()?;
+            println!("{}:\n{}", circuit.name, circuit.stats());
+            for name in explain {
+                println!("{}", circuit.explain_taint(&name)?);
+            }
+            circuit.propagate_secure();
+            println!("secrets:\n{}", circuit.secret_report());
+            if let Some(order) = order {
+                if !secrets.is_empty() {
+                    circuit.select_secrets(secrets);
+                }
+                mask_circuit(&mut circuit, order, gadget, no_balance)?;
+                if reuse_randomness {
+                    println!("Fresh randomness: {}", circuit.reuse_randomness()?);
+                }
+                if expand {
+                    circuit.expand_gadgets();
+                }
+                println!(
+                    "{} (masked, order {}, {} gadgets):\n{}",
+                    circuit.name,
+                    order,
+                    gadget,
+                    circuit.stats()
+                );
+            }
+            Ok(())
+        }
+        Command::Convert { input, output } => {
+            let circuit = input.load_circuit()?;
+            let name = circuit.name.clone();
+            write_circuit(&circuit, &output, &name)
+        }
+    }
+}
+
+fn main() -> ExitCode {
+    match run(Cli::parse()) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests{
     use crate::*;
 
     #[test]
-    fn samples()  -> Result<(), AppError> {
+    fn samples() -> Result<(), AppError> {
         let netlist_path = concat!(
             env!("CARGO_MANIFEST_DIR"),
             "/",
             "tests/hdl/simple/simple_1.json"
         );
-        println!("reading netlist: {}", netlist_path);
-    
         let netlist = NetlistAndLibrary::from_path(netlist_path)?;
-    
-        println!("Constructing circuit");
         let mut circuit = circuit::Circuit::try_from(&netlist)?;
-    
-        let dot_file = format!("{}_orig.dot", circuit.name);
-        println!("Writing DOT to {}", dot_file);
-        circuit.dump_to_file(&dot_file).expect("Writing dot failed");
-    
-        println!("Propagating secure");
-        circuit.mask(1);
-    
-        let dot_file = format!("{}.dot", circuit.name);
-        println!("Writing DOT to {}", dot_file);
-        circuit.dump_to_file(&dot_file).expect("Writing dot failed");
-    
-        // circuit.
-    
+
+        let output_dir = std::env::temp_dir().join("masquerade_samples");
+        let orig_path = output_path(&output_dir, &format!("{}_orig", circuit.name), "dot")?;
+        write_dot(&circuit, &orig_path)?;
+        circuit.mask(1)?;
+        let masked_path = output_path(&output_dir, &circuit.name, "dot")?;
+        write_dot(&circuit, &masked_path)?;
+        assert!(std::fs::read_to_string(orig_path)?.starts_with("digraph"));
+        assert!(std::fs::read_to_string(masked_path)?.starts_with("digraph"));
         Ok(())
     }
-}
 
+    #[test]
+    fn cli_args() {
+        let cli = Cli::try_parse_from([
+            "masquerade",
+            "mask",
+            "tests/hdl/simple/simple_1.json",
+            "--order",
+            "2",
+            "--gadget",
+            "hpc2",
+            "--format",
+            "dot",
+            "-O",
+            "out",
+        ])
+        .unwrap();
+        match cli.command {
+            Command::Mask {
+                input,
+                order,
+                gadget,
+                reuse_randomness,
+                secrets,
+                no_balance,
+                expand,
+                check,
+                output,
+            } => {
+                assert_eq!(input.netlist, PathBuf::from("tests/hdl/simple/simple_1.json"));
+                assert_eq!(order, 2);
+                assert_eq!(gadget, GadgetKind::Hpc2);
+                assert!(!reuse_randomness);
+                assert!(!no_balance);
+                assert!(secrets.is_empty());
+                assert!(!expand);
+                assert_eq!(check, None);
+                assert_eq!(output.format, OutputFormat::Dot);
+                assert_eq!(output.output_dir, PathBuf::from("out"));
+            }
+            c => panic!("unexpected command {:?}", c),
+        }
+        assert!(Cli::try_parse_from(["masquerade", "stats"]).is_err());
+    }
+}
@@ -1,7 +1,7 @@
 use serde_derive::{Deserialize, Serialize};
 use std::collections::HashMap;
 
-use crate::utils::bool_from_int;
+use crate::utils::{bool_from_int, bool_to_int};
 
 pub type SignalId = u32;
 
@@ -108,7 +108,11 @@ pub struct Port {
 #[derive(Clone, Serialize, Deserialize, Debug, Eq, PartialEq)]
 pub struct Cell {
     /// Indicates an internal/auto-generated name that starts with `$`
-    #[serde(default, deserialize_with = "bool_from_int")]
+    #[serde(
+        default,
+        deserialize_with = "bool_from_int",
+        serialize_with = "bool_to_int"
+    )]
     pub hide_name: bool,
     /// Name of the type of this cell
     #[serde(rename = "type")]
@@ -148,7 +152,11 @@ pub struct Memory {
 #[derive(Clone, Serialize, Deserialize, Debug, Eq, PartialEq)]
 pub struct Netname {
     /// Indicates an internal/auto-generated name that starts with `$`
-    #[serde(default, deserialize_with = "bool_from_int")]
+    #[serde(
+        default,
+        deserialize_with = "bool_from_int",
+        serialize_with = "bool_to_int"
+    )]
     pub hide_name: bool,
     /// Bit value(s) that should be given this name
     pub bits: Vec<BitVal>,
@@ -152,7 +152,7 @@ impl Netlist {
             if module.is_top() {
                 return Some((name, module));
             }
-            if first_none_blackbox.is_none() && module.is_blackbox() {
+            if first_none_blackbox.is_none() && !module.is_blackbox() {
                 first_none_blackbox = Some((name, module));
             }
         }
@@ -1,7 +1,4 @@
 pub mod json_netlist;
 mod json_netlist_impl;
-// pub mod verilog_1;
-pub mod verilog_lalrpop;
-// pub mod verilog_netlist;
-// mod verilog;
-// mod verilog_chumsky;
+pub mod verilog;
+mod verilog_writer;
@@ -4,7 +4,7 @@ use std::ops::Range;
 use std::str::FromStr;
 use bitvec::prelude::Lsb0;
 use bitvec::view::BitView;
-use crate::logic::LogicVec1;
+use crate::logic::Logic;
 
 pub type Map<K, V> = BTreeMap<K, V>;
 
@@ -46,11 +46,16 @@ impl ConstExpr {
 
 // Wires and expressions can be more than 64 bits
 #[derive(Clone, Debug, Default, Eq, Hash, PartialEq)]
-struct NumberValue(usize);
+struct NumberValue {
+    value: usize,
+    // bits which are `x` or `z`
+    x_mask: usize,
+    z_mask: usize,
+}
 
 impl From<&NumberValue> for u64 {
     fn from(value: &NumberValue) -> Self {
-        value.0 as u64
+        value.value as u64
     }
 }
 
@@ -58,16 +63,53 @@ impl TryFrom<&NumberValue> for u32 {
     type Error = ();
 
     fn try_from(value: &NumberValue) -> Result<Self, Self::Error> {
-        Ok(value.0 as u32)
+        Ok(value.value as u32)
     }
 }
 
 impl NumberValue {
     fn from_str_radix(s: &str, radix: u32) -> Result<Self, ParseIntError> {
-        usize::from_str_radix(s, radix).map(|v| Self(v))
+        let is_xz = |c: char| matches!(c, 'x' | 'X' | 'z' | 'Z');
+        let value = usize::from_str_radix(&s.replace(is_xz, "0"), radix)?;
+        let digit_bits = match radix {
+            2 => 1,
+            8 => 3,
+            16 => 4,
+            _ => 0,
+        };
+        let (mut x_mask, mut z_mask) = (0, 0);
+        if digit_bits > 0 {
+            for (i, c) in s.chars().rev().enumerate() {
+                let mask = ((1usize << digit_bits) - 1)
+                    .checked_shl((i * digit_bits) as u32)
+                    .unwrap_or(0);
+                match c {
+                    'x' | 'X' => x_mask |= mask,
+                    'z' | 'Z' => z_mask |= mask,
+                    _ => (),
+                }
+            }
+        }
+        Ok(Self {
+            value,
+            x_mask,
+            z_mask,
+        })
     }
     pub fn bit(&self, idx: SizeType) -> bool {
-        self.0.view_bits::<Lsb0>()[idx as usize]
+        (idx as usize) < usize::BITS as usize && self.value.view_bits::<Lsb0>()[idx as usize]
+    }
+    pub fn logic_bit(&self, idx: SizeType) -> Logic {
+        let mask_bit = |mask: usize| (idx as usize) < usize::BITS as usize && (mask >> idx) & 1 == 1;
+        if mask_bit(self.z_mask) {
+            Logic::Z
+        } else if mask_bit(self.x_mask) {
+            Logic::X
+        } else if self.bit(idx) {
+            Logic::One
+        } else {
+            Logic::Zero
+        }
     }
 }
 
@@ -91,6 +133,20 @@ impl Number {
             _ => self.value.bit(idx),
         }
     }
+    /// bit value including `x` and `z`
+    pub fn logic_bit(&self, idx: SizeType) -> Logic {
+        match self.size {
+            Some(w) if idx >= w => Logic::Zero,
+            _ => self.value.logic_bit(idx),
+        }
+    }
+    /// unsized numbers are 32 bits wide
+    pub fn width(&self) -> SizeType {
+        self.size.unwrap_or(32)
+    }
+    pub fn as_usize(&self) -> usize {
+        self.value.value
+    }
 }
 
 impl TryFrom<&Number> for SizeType {
@@ -114,8 +170,9 @@ impl From<Option<&str>> for NetType {
     }
 }
 
+/// a net or a slice of it, with its location in the source
 #[derive(Clone, Debug, Eq, Hash, PartialEq)]
-pub struct NetSlice(pub Ident, pub Option<Slice>);
+pub struct NetSlice(pub Ident, pub Option<Slice>, pub Range<usize>);
 
 pub(crate) type Attributes = Map<Ident, Expr>;
 
@@ -123,6 +180,8 @@ pub(crate) type Attributes = Map<Ident, Expr>;
 pub struct ModuleDeclaration {
     pub attrs: Attributes,
     pub name: Ident,
+    /// location of the module name in the source
+    pub span: Range<usize>,
     pub ports: Vec<Port>,
     pub body: Vec<ModuleItem>,
 }
@@ -149,28 +208,6 @@ impl ModuleDeclaration {
             _ => None,
         }).flatten().collect()
     }
-
-    // assuming default value of signals is Z
-    pub fn continuous_assigns(&self, net_widths: HashMap<String, SizeType>) -> HashMap<String, LogicVec1> {
-        let ret = HashMap::new();
-        let assigns = self.body.iter().filter_map(|i| match i {
-            ModuleItem::ContinuousAssign(assigns) => Some(assigns),
-            _ => None,
-        }).flatten();
-        // for a in assigns {
-        //     match (&a.0, &a.1) {
-        //         (
-        //             LValue::NetSlice(NetSlice(ident, slice)),
-        //             Expr::Primary(Primary::Number(num))) => {
-        //             for idx in slice.unwrap_or_default().range() {
-        //                 // bit_assigns.push(SizeType::from(slice)
-        //             }
-        //         }
-        //         (_, _) => todo!("Not implemented!")
-        //     }
-        // }
-        ret
-    }
 }
 
 #[derive(Clone, Debug, Eq, Hash, PartialEq)]
@@ -179,8 +216,9 @@ pub enum LValue {
     Concatenation(Vec<LValue>),
 }
 
+/// `lvalue = expr`, with its location in the source
 #[derive(Clone, Debug, Eq, Hash, PartialEq)]
-pub struct NetAssignment(pub LValue, pub Expr);
+pub struct NetAssignment(pub LValue, pub Expr, pub Range<usize>);
 
 #[derive(Clone, Debug, Eq, Hash, PartialEq, strum::EnumString)]
 #[strum(serialize_all = "lowercase")]
@@ -217,6 +255,18 @@ impl ConstRange {
     pub fn range(&self) -> Range<SizeType> {
         self.hi()..self.lo()
     }
+    /// position of bit `idx` in the LSB-first list of bits, i.e. relative to the right bound
+    pub fn position(&self, idx: SizeType) -> Option<usize> {
+        if self.hi() >= self.lo() {
+            (self.lo()..=self.hi())
+                .contains(&idx)
+                .then(|| (idx - self.lo()) as usize)
+        } else {
+            (self.hi()..=self.lo())
+                .contains(&idx)
+                .then(|| (self.lo() - idx) as usize)
+        }
+    }
     pub fn from_const_expr(start: &ConstExpr, end: &ConstExpr) -> Result<Self, ()> {
         let do_eval = |s: &ConstExpr| s.eval().and_then(|n| SizeType::try_from(&n).ok());
         match (do_eval(start), do_eval(end)) {
@@ -239,8 +289,9 @@ pub enum Connections {
     Named(Vec<(Ident, Expr)>),
 }
 
+/// instance name and connections, with their location in the source
 #[derive(Clone, Debug, Eq, Hash, PartialEq)]
-pub struct HierarchicalInstance(pub Ident, pub Connections);
+pub struct HierarchicalInstance(pub Ident, pub Connections, pub Range<usize>);
 
 #[derive(Clone, Debug, Eq, Hash, PartialEq)]
 pub enum ModuleItem {
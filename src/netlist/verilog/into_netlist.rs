@@ -0,0 +1,532 @@
+use std::collections::{HashMap, HashSet};
+use std::ops::Range;
+
+use crate::logic::Logic;
+use crate::netlist::json_netlist::{
+    AttributeVal, BitVal, Cell, ConstBit, Module, Netlist, Netname, Port, PortDirection, SignalId,
+};
+
+use super::ast::{
+    Attributes, Connections, ConstRange, Direction, Expr, HierarchicalInstance, LValue,
+    ModuleDeclaration, ModuleItem, NetAssignment, NetSlice, Number, Primary, SizeType, Slice,
+};
+
+impl From<&Direction> for PortDirection {
+    fn from(value: &Direction) -> Self {
+        match value {
+            Direction::Input => PortDirection::Input,
+            Direction::Output => PortDirection::Output,
+            Direction::InOut => PortDirection::InOut,
+        }
+    }
+}
+
+/// An error in the structure of a module, located at the offending item or at the name of the
+/// module
+#[derive(Debug)]
+pub(super) struct ElaborationError {
+    pub span: Range<usize>,
+    pub message: String,
+    /// describes the location, e.g. "in this module"
+    pub label: &'static str,
+}
+
+fn elaboration_error<T>(decl: &ModuleDeclaration, message: String) -> Result<T, ElaborationError> {
+    Err(ElaborationError {
+        span: decl.span.clone(),
+        message,
+        label: "in this module",
+    })
+}
+
+/// error at an item of a module, e.g. a net slice, an assignment or an instance
+fn item_error<T>(span: &Range<usize>, message: String) -> Result<T, ElaborationError> {
+    Err(ElaborationError {
+        span: span.clone(),
+        message,
+        label: "here",
+    })
+}
+
+/// names generated by synthesis tools, e.g. `_0123_` or `$abc$123`
+fn is_auto_name(name: &str) -> bool {
+    name.starts_with('$')
+        || (name.len() > 2
+            && name.starts_with('_')
+            && name.ends_with('_')
+            && name[1..name.len() - 1].chars().all(|c| c.is_ascii_digit()))
+}
+
+fn unescape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c == '\\' {
+            match chars.next() {
+                Some('n') => out.push('\n'),
+                Some('t') => out.push('\t'),
+                Some(c) => out.push(c),
+                None => out.push('\\'),
+            }
+        } else {
+            out.push(c);
+        }
+    }
+    out
+}
+
+fn const_bit(number: &Number, idx: SizeType) -> BitVal {
+    BitVal::Constant(match number.logic_bit(idx) {
+        Logic::Zero => ConstBit::_0,
+        Logic::One => ConstBit::_1,
+        Logic::X => ConstBit::X,
+        Logic::Z => ConstBit::Z,
+    })
+}
+
+#[derive(Debug, Default)]
+struct Net {
+    range: Option<ConstRange>,
+    attributes: Attributes,
+    direction: Option<Direction>,
+    /// LSB first
+    bits: Vec<BitVal>,
+}
+
+struct ModuleElaborator<'a> {
+    decl: &'a ModuleDeclaration,
+    nets: HashMap<&'a str, Net>,
+    next_signal: SignalId,
+    /// signals which are aliases of other bits through continuous assignments
+    aliases: HashMap<SignalId, BitVal>,
+}
+
+impl<'a> ModuleElaborator<'a> {
+    fn new(decl: &'a ModuleDeclaration) -> Self {
+        Self {
+            decl,
+            nets: HashMap::new(),
+            // Yosys reserves 0 and 1 for the constants
+            next_signal: 2,
+            aliases: HashMap::new(),
+        }
+    }
+
+    fn error<T>(&self, msg: String) -> Result<T, ElaborationError> {
+        elaboration_error(self.decl, msg)
+    }
+
+    fn fresh_signal(&mut self) -> BitVal {
+        let sig = self.next_signal;
+        self.next_signal += 1;
+        BitVal::Signal(sig)
+    }
+
+    fn declare(
+        &mut self,
+        name: &'a str,
+        range: &Option<ConstRange>,
+        attributes: &Attributes,
+        direction: Option<&Direction>,
+    ) -> Result<(), ElaborationError> {
+        let net = self.nets.entry(name).or_default();
+        if let Some(range) = range {
+            match &net.range {
+                Some(r) if r != range => {
+                    return self.error(format!("conflicting declarations of net {}", name))
+                }
+                _ => net.range = Some(range.clone()),
+            }
+        }
+        net.attributes
+            .extend(attributes.iter().map(|(k, v)| (k.clone(), v.clone())));
+        if let Some(direction) = direction {
+            net.direction = Some(direction.clone());
+        }
+        Ok(())
+    }
+
+    fn declare_nets(&mut self) -> Result<(), ElaborationError> {
+        for item in self.decl.body.iter() {
+            match item {
+                ModuleItem::PortDeclaration(attrs, range, idents, direction) => {
+                    for ident in idents {
+                        self.declare(ident, range, attrs, Some(direction))?;
+                    }
+                }
+                ModuleItem::NetDeclaration(attrs, _, range, idents) => {
+                    for ident in idents {
+                        self.declare(ident, range, attrs, None)?;
+                    }
+                }
+                _ => (),
+            }
+        }
+        for port in self.decl.ports.iter() {
+            let direction = self
+                .nets
+                .get(port.as_str())
+                .and_then(|net| net.direction.as_ref());
+            if direction.is_none() {
+                return self.error(format!("missing direction for port {}", port));
+            }
+        }
+        // allocate signals in declaration order of the ports, then the other nets
+        let mut names: Vec<&str> = self.decl.ports.iter().map(|p| p.as_str()).collect();
+        let mut others: Vec<&str> = self
+            .nets
+            .keys()
+            .filter(|n| !self.decl.ports.iter().any(|p| p == *n))
+            .copied()
+            .collect();
+        others.sort();
+        names.extend(others);
+        for name in names {
+            let width = self.nets[name].range.as_ref().map_or(1, |r| r.width());
+            let bits = (0..width).map(|_| self.fresh_signal()).collect();
+            self.nets.get_mut(name).unwrap().bits = bits;
+        }
+        Ok(())
+    }
+
+    /// bits of a net, implicitly declaring undeclared nets as 1-bit wires
+    fn net_bits(&mut self, name: &'a str) -> &Vec<BitVal> {
+        if !self.nets.contains_key(name) {
+            let bits = vec![self.fresh_signal()];
+            self.nets.insert(
+                name,
+                Net {
+                    bits,
+                    ..Default::default()
+                },
+            );
+        }
+        &self.nets[name].bits
+    }
+
+    fn const_index(expr: &Expr, span: &Range<usize>) -> Result<SizeType, ElaborationError> {
+        match expr {
+            Expr::Primary(Primary::Number(n)) => Ok(n.as_usize() as SizeType),
+            _ => item_error(span, format!("non-constant index {:?}", expr)),
+        }
+    }
+
+    fn net_slice_bits(&mut self, net_slice: &'a NetSlice) -> Result<Vec<BitVal>, ElaborationError> {
+        let NetSlice(name, slice, span) = net_slice;
+        let bits = self.net_bits(name).clone();
+        let Some(Slice(hi, lo)) = slice else {
+            return Ok(bits);
+        };
+        let range = self.nets[name.as_str()].range.clone().unwrap_or_default();
+        let position = |idx: SizeType| {
+            range.position(idx).map_or_else(
+                || item_error(span, format!("index {} out of range for net {}", idx, name)),
+                Ok,
+            )
+        };
+        let hi = position(Self::const_index(hi, span)?)?;
+        let lo = match lo {
+            Some(lo) => position(Self::const_index(lo, span)?)?,
+            None => hi,
+        };
+        if lo <= hi {
+            Ok(bits[lo..=hi].to_vec())
+        } else {
+            Ok(bits[hi..=lo].iter().rev().copied().collect())
+        }
+    }
+
+    /// bits of an expression, LSB first. Errors are located at the net slices or else at the
+    /// `span` of the item containing the expression.
+    fn expr_bits(
+        &mut self,
+        expr: &'a Expr,
+        span: &Range<usize>,
+    ) -> Result<Vec<BitVal>, ElaborationError> {
+        match expr {
+            Expr::Primary(Primary::Number(n)) => {
+                Ok((0..n.width()).map(|idx| const_bit(n, idx)).collect())
+            }
+            Expr::Primary(Primary::StringLit(s)) => {
+                item_error(span, format!("unexpected string \"{}\" in expression", s))
+            }
+            Expr::Primary(Primary::NetSlice(ns)) => self.net_slice_bits(ns),
+            Expr::Concatenation(exprs) => {
+                let mut bits = Vec::new();
+                // the first element of a concatenation is the most significant
+                for e in exprs.iter().rev() {
+                    bits.extend(self.expr_bits(e, span)?);
+                }
+                Ok(bits)
+            }
+        }
+    }
+
+    fn lvalue_bits(&mut self, lvalue: &'a LValue) -> Result<Vec<BitVal>, ElaborationError> {
+        match lvalue {
+            LValue::NetSlice(ns) => self.net_slice_bits(ns),
+            LValue::Concatenation(lvalues) => {
+                let mut bits = Vec::new();
+                for lv in lvalues.iter().rev() {
+                    bits.extend(self.lvalue_bits(lv)?);
+                }
+                Ok(bits)
+            }
+        }
+    }
+
+    fn resolve(&self, bit: BitVal) -> BitVal {
+        let mut bit = bit;
+        while let BitVal::Signal(sig) = bit {
+            match self.aliases.get(&sig) {
+                Some(b) => bit = *b,
+                None => break,
+            }
+        }
+        bit
+    }
+
+    fn assign(&mut self, assignment: &'a NetAssignment) -> Result<(), ElaborationError> {
+        let NetAssignment(lvalue, expr, span) = assignment;
+        let lhs = self.lvalue_bits(lvalue)?;
+        let rhs = self.expr_bits(expr, span)?;
+        // the right-hand side is truncated or zero-extended to the width of the left-hand side
+        let rhs = rhs
+            .into_iter()
+            .chain(std::iter::repeat(BitVal::Constant(ConstBit::_0)));
+        for (l, r) in lhs.into_iter().zip(rhs) {
+            let BitVal::Signal(l) = l else {
+                unreachable!("net bits are always signals")
+            };
+            let r = self.resolve(r);
+            if r == BitVal::Signal(l) {
+                continue;
+            }
+            if self.aliases.insert(l, r).is_some() {
+                return item_error(span, format!("signal {} has multiple drivers", l));
+            }
+        }
+        Ok(())
+    }
+
+    fn attributes(
+        &self,
+        attrs: &Attributes,
+    ) -> Result<HashMap<String, AttributeVal>, ElaborationError> {
+        attrs
+            .iter()
+            .map(|(k, v)| {
+                let value = match v {
+                    Expr::Primary(Primary::Number(n)) => AttributeVal::Number(n.as_usize()),
+                    Expr::Primary(Primary::StringLit(s)) => AttributeVal::String(unescape(s)),
+                    _ => return self.error(format!("non-constant value for attribute {}", k)),
+                };
+                Ok((k.clone(), value))
+            })
+            .collect()
+    }
+
+    fn cell<F>(
+        &mut self,
+        cell_type: &str,
+        attrs: &Attributes,
+        instance: &'a HierarchicalInstance,
+        definitions: &HashMap<&str, &ModuleDeclaration>,
+        port_direction: &F,
+    ) -> Result<(String, Cell), ElaborationError>
+    where
+        F: Fn(&str, &str) -> Option<PortDirection>,
+    {
+        let HierarchicalInstance(name, connections, span) = instance;
+        let definition = definitions.get(cell_type);
+        let connections = match connections {
+            Connections::Named(conns) => conns
+                .iter()
+                .map(|(port, expr)| Ok((port.clone(), self.expr_bits(expr, span)?)))
+                .collect::<Result<HashMap<_, _>, ElaborationError>>()?,
+            Connections::Ordered(exprs) => {
+                let Some(definition) = definition else {
+                    return item_error(
+                        span,
+                        format!(
+                            "ordered connections of {} require a definition of module {}",
+                            name, cell_type
+                        ),
+                    );
+                };
+                if exprs.len() > definition.ports.len() {
+                    return item_error(span, format!("too many connections for instance {}", name));
+                }
+                definition
+                    .ports
+                    .iter()
+                    .zip(exprs)
+                    .map(|(port, expr)| Ok((port.clone(), self.expr_bits(expr, span)?)))
+                    .collect::<Result<HashMap<_, _>, ElaborationError>>()?
+            }
+        };
+        let definition_ports = definition.map(|d| d.port_decls());
+        let mut port_directions = HashMap::new();
+        for port in connections.keys() {
+            let direction = definition_ports
+                .as_ref()
+                .and_then(|ports| ports.get(port))
+                .map(|(_, _, direction)| PortDirection::from(direction))
+                .or_else(|| port_direction(cell_type, port));
+            match direction {
+                Some(direction) => port_directions.insert(port.clone(), direction),
+                None => {
+                    return item_error(
+                        span,
+                        format!(
+                            "unknown direction of port {} of cell type {}",
+                            port, cell_type
+                        ),
+                    )
+                }
+            };
+        }
+        let cell = Cell {
+            hide_name: is_auto_name(name),
+            cell_type: cell_type.to_owned(),
+            parameters: HashMap::new(),
+            attributes: self.attributes(attrs)?,
+            port_directions,
+            connections,
+        };
+        Ok((name.clone(), cell))
+    }
+
+    fn elaborate<F>(
+        mut self,
+        definitions: &HashMap<&str, &ModuleDeclaration>,
+        port_direction: &F,
+    ) -> Result<Module, ElaborationError>
+    where
+        F: Fn(&str, &str) -> Option<PortDirection>,
+    {
+        let decl = self.decl;
+        self.declare_nets()?;
+
+        let mut module = Module {
+            attributes: self.attributes(&decl.attrs)?,
+            ..Default::default()
+        };
+        let is_blackbox = !decl.body.iter().any(|item| {
+            matches!(
+                item,
+                ModuleItem::ModuleInstantiation(..) | ModuleItem::ContinuousAssign(..)
+            )
+        });
+        if is_blackbox {
+            module
+                .attributes
+                .entry("blackbox".to_owned())
+                .or_insert(AttributeVal::Number(1));
+        }
+
+        for item in decl.body.iter() {
+            match item {
+                ModuleItem::ContinuousAssign(assigns) => {
+                    for assign in assigns {
+                        self.assign(assign)?;
+                    }
+                }
+                ModuleItem::ModuleInstantiation(attrs, cell_type, instances) => {
+                    for instance in instances {
+                        let (name, cell) =
+                            self.cell(cell_type, attrs, instance, definitions, port_direction)?;
+                        if module.cells.insert(name.clone(), cell).is_some() {
+                            return item_error(
+                                &instance.2,
+                                format!("duplicate instance name {}", name),
+                            );
+                        }
+                    }
+                }
+                _ => (),
+            }
+        }
+
+        for cell in module.cells.values_mut() {
+            for bits in cell.connections.values_mut() {
+                for bit in bits.iter_mut() {
+                    *bit = self.resolve(*bit);
+                }
+            }
+        }
+        for (name, net) in self.nets.iter() {
+            let bits: Vec<_> = net.bits.iter().map(|b| self.resolve(*b)).collect();
+            if let Some(direction) = &net.direction {
+                module.ports.insert(
+                    name.to_string(),
+                    Port {
+                        direction: direction.into(),
+                        bits: bits.clone(),
+                        offset: net
+                            .range
+                            .as_ref()
+                            .map_or(0, |r| r.hi().min(r.lo()) as usize),
+                    },
+                );
+            }
+            module.netnames.insert(
+                name.to_string(),
+                Netname {
+                    hide_name: is_auto_name(name),
+                    bits,
+                    attributes: self.attributes(&net.attributes)?,
+                },
+            );
+        }
+        Ok(module)
+    }
+}
+
+/// Elaborate parsed structural Verilog modules into a netlist.
+/// Directions of the ports of cells which are not defined as modules are looked up using `port_direction`.
+/// Unless a module is marked as `top`, the first non-blackbox module which is not instantiated by other modules is the top module.
+pub(crate) fn elaborate<F>(
+    modules: &[ModuleDeclaration],
+    port_direction: F,
+) -> Result<Netlist, ElaborationError>
+where
+    F: Fn(&str, &str) -> Option<PortDirection>,
+{
+    let definitions: HashMap<&str, &ModuleDeclaration> =
+        modules.iter().map(|m| (m.name.as_str(), m)).collect();
+    let instantiated: HashSet<&str> = modules
+        .iter()
+        .flat_map(|m| m.body.iter())
+        .filter_map(|item| match item {
+            ModuleItem::ModuleInstantiation(_, cell_type, _) => Some(cell_type.as_str()),
+            _ => None,
+        })
+        .collect();
+
+    let mut netlist = Netlist::new("masquerade");
+    let mut top = None;
+    for decl in modules {
+        let module = ModuleElaborator::new(decl).elaborate(&definitions, &port_direction)?;
+        if top.is_none() && !module.is_blackbox() && !instantiated.contains(decl.name.as_str()) {
+            top = Some(decl.name.clone());
+        }
+        if netlist.modules.insert(decl.name.clone(), module).is_some() {
+            return elaboration_error(
+                decl,
+                format!("duplicate definition of module {}", decl.name),
+            );
+        }
+    }
+    if !netlist.modules.values().any(|m| m.is_top()) {
+        if let Some(top) = top {
+            netlist
+                .modules
+                .get_mut(&top)
+                .unwrap()
+                .attributes
+                .insert("top".to_owned(), AttributeVal::Number(1));
+        }
+    }
+    Ok(netlist)
+}
@@ -1 +1,5 @@
-mod reader;
\ No newline at end of file
+mod ast;
+mod into_netlist;
+mod reader;
+
+pub use reader::{parse_file, parse_str, Error};
@@ -1,51 +1,64 @@
-use std::collections::HashMap;
-use pest::Parser;
-use pest_derive::Parser;
-use simple_error::SimpleError;
-use crate::netlist::json_netlist::{AttributeVal, Module};
-
-#[derive(Parser)]
-#[grammar = "netlist/verilog/verilog.pest"]
-pub struct VerilogParser;
-
-type Pair<'i> = pest::iterators::Pair<'i, Rule>;
-
-
-#[inline]
-fn parse_module(s: &str, p: Pair) -> (String, Module) {
-    let mut inner = p.into_inner();
-
-    let mut defs = Vec::new();
-    let mut assigns = Vec::new();
-    let mut cells = Vec::new();
-    // for stmt in stmts {
-    //     match stmt.as_rule() {
-    //         Rule::wiredef => parse_and_push_wiredef(s, stmt, &mut defs),
-    //         Rule::assign => assigns.push(parse_assign(s, stmt)),
-    //         Rule::cell => cells.push(parse_cell(s, stmt)),
-    //         _ => unreachable!()
-    //     }
-    // }
-    (name, SVerilogModule{ ports, defs, assigns, cells })
+use std::path::Path;
+
+use lalrpop_util::lalrpop_mod;
+
+use crate::netlist::json_netlist::{Netlist, PortDirection};
+use crate::utils::{parse_error_report, source_report};
+
+use super::into_netlist::{elaborate, ElaborationError};
+
+lalrpop_mod!(pub grammar, "/netlist/verilog/grammar.rs");
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error(transparent)]
+    IoError(#[from] std::io::Error),
+    /// syntax error, with a report pointing to the source
+    #[error("Error while parsing Verilog netlist:\n{0}")]
+    ParseError(String),
+    /// error in the structure of a parsed module, with a report pointing to the source
+    #[error("Error while elaborating Verilog netlist:\n{0}")]
+    ElaborationError(String),
+}
+
+/// Parse a structural Verilog netlist into a `Netlist`. `name` identifies the source in error reports.
+/// `port_direction` gives the direction of a port of a cell type which is not defined in `src`.
+pub fn parse_str<F>(name: &str, src: &str, port_direction: F) -> Result<Netlist, Error>
+where
+    F: Fn(&str, &str) -> Option<PortDirection>,
+{
+    let parser = grammar::NetlistParser::new();
+    let modules = parser
+        .parse(src)
+        .map_err(|e| Error::ParseError(parse_error_report(name, src, e)))?;
+    elaborate(&modules, port_direction).map_err(
+        |ElaborationError {
+             span,
+             message,
+             label,
+         }| Error::ElaborationError(source_report(name, src, span, &message, label)),
+    )
 }
 
-pub fn parse_verilog(s: &str) -> Result<Verilog, SimpleError> {
-    let parse_result = VerilogParser::parse(Rule::file, &s)?;
-    Ok(Verilog {
-        modules:
-        parse_result.into_inner()
-            .filter(|p| p.as_rule() != Rule::EOI)
-            .map(|p| parse_module(&s, p)).collect()
-    })
+/// Parse a structural Verilog netlist file into a `Netlist`. See [parse_str].
+pub fn parse_file<P, F>(path: P, port_direction: F) -> Result<Netlist, Error>
+where
+    P: AsRef<Path>,
+    F: Fn(&str, &str) -> Option<PortDirection>,
+{
+    let src = std::fs::read_to_string(&path)?;
+    parse_str(&path.as_ref().to_string_lossy(), &src, port_direction)
 }
 
 #[cfg(test)]
 mod tests {
+    use super::super::ast::*;
     use super::*;
+    use crate::netlist::json_netlist::{AttributeVal, BitVal, ConstBit};
 
     #[test]
-    fn simple() {
-        let verilog: &str = r#"
+    fn test_parse_verilog() {
+        let test_verilog_code: &str = r#"
 /* Generated by Yosys 0.25+0 (git sha1 c34d308bb, clang 14.0.0 -fPIC -Os) */
 /* some comment */
 module OR(A, B);
@@ -77,13 +90,13 @@ EN_state_in, RDY_state_in);
   (*MASQ="clock"*) input clk1;
   output [1:3] data_out;
   wire [1234:45] mywire;
-
+  
   wire \escaped_name[[a]} ;
-
+  
   wire t;
   OR _blabla_ ( .A(a), .B (b), .C(a) );
   XNOR blabla2 ( .A(netname), .B (1'b0) );
-
+  
   wire t234;
   (* src = ".v:123" *)
   NOT #(
@@ -98,10 +111,169 @@ EN_state_in, RDY_state_in);
 endmodule
 "#;
 
-        let result = VerilogParser::parse(Rule::file, verilog).unwrap();
-        println!("{:?}", result);
+        let parser = grammar::NetlistParser::new();
+        log::debug!("Parsing netlist with LALRPOP.");
+        let result: Result<Vec<ModuleDeclaration>, _> = parser.parse(test_verilog_code);
+        assert!(result.is_ok());
+    }
+
+    #[test]
+    fn elaborate_netlist() -> Result<(), Error> {
+        let src = r#"
+module top(clk, a, b, y, z);
+  (* MASQ = "clock" *)
+  input clk;
+  (* MASQ = "secure" *)
+  input [3:0] a;
+  input [0:1] b;
+  output [2:0] y;
+  output z;
+  wire [1:0] t;
+  AND _1_ (.A(a[3]), .B(b[0]), .Y(t[1]));
+  XOR g2 (.A({ a[1:0], b }), .B(4'b1x0z), .Y(t[0]));
+  assign y = { t, 1'b1 };
+  assign z = a[2];
+endmodule
+"#;
+        let netlist = parse_str("top.v", src, |_, port| {
+            Some(if port == "Y" {
+                PortDirection::Output
+            } else {
+                PortDirection::Input
+            })
+        })?;
+        let (name, top) = netlist.get_top().unwrap();
+        assert_eq!(name, "top");
+        let a = &top.netnames["a"];
+        let b = &top.netnames["b"];
+        assert_eq!(
+            a.attributes["MASQ"],
+            AttributeVal::String("secure".to_owned())
+        );
+        assert_eq!(top.ports["b"].bits, b.bits);
+
+        let and = &top.cells["_1_"];
+        assert!(and.hide_name);
+        assert_eq!(and.connections["A"], vec![a.bits[3]]);
+        // `b` is declared as [0:1], so b[0] is its MSB
+        assert_eq!(and.connections["B"], vec![b.bits[1]]);
+
+        let xor = &top.cells["g2"];
+        assert!(!xor.hide_name);
+        assert_eq!(
+            xor.connections["A"],
+            vec![b.bits[0], b.bits[1], a.bits[0], a.bits[1]]
+        );
+        assert_eq!(
+            xor.connections["B"],
+            vec![
+                BitVal::Constant(ConstBit::Z),
+                BitVal::Constant(ConstBit::_0),
+                BitVal::Constant(ConstBit::X),
+                BitVal::Constant(ConstBit::_1),
+            ]
+        );
+        assert_eq!(
+            top.ports["y"].bits,
+            vec![
+                BitVal::Constant(ConstBit::_1),
+                xor.connections["Y"][0],
+                and.connections["Y"][0],
+            ]
+        );
+        assert_eq!(top.ports["z"].bits, vec![a.bits[2]]);
+        Ok(())
+    }
+
+    fn verilog_files(dir: &Path, files: &mut Vec<std::path::PathBuf>) -> std::io::Result<()> {
+        for entry in std::fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path.is_dir() {
+                verilog_files(&path, files)?;
+            } else if path.extension().is_some_and(|ext| ext == "v") {
+                files.push(path);
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn parse_test_files() -> Result<(), Error> {
+        // behavioral sources of the test designs, which are not netlists
+        const RTL_SOURCES: &[&str] = &["simple_1.v", "mkPerm.v"];
+
+        let mut files = Vec::new();
+        verilog_files(
+            Path::new(concat!(env!("CARGO_MANIFEST_DIR"), "/tests")),
+            &mut files,
+        )?;
+        assert!(!files.is_empty());
+        for path in files {
+            let file_name = path.file_name().unwrap().to_string_lossy();
+            let result = parse_file(&path, |_, port| {
+                Some(match port {
+                    "Y" | "Z" | "Q" => PortDirection::Output,
+                    _ => PortDirection::Input,
+                })
+            });
+            if RTL_SOURCES.contains(&file_name.as_ref()) {
+                match result {
+                    Err(Error::ParseError(report)) => {
+                        assert!(report.contains(file_name.as_ref()), "{}", report);
+                    }
+                    r => panic!("{}: expected a syntax error, got {:?}", file_name, r),
+                }
+            } else {
+                let netlist = result?;
+                assert!(netlist.get_top().is_some(), "{}: no top module", file_name);
+            }
+        }
+        Ok(())
     }
 
     #[test]
-    fn simple_fail() {}
-}
\ No newline at end of file
+    fn elaboration_error() {
+        let src =
+            "module top(a, y);\n  input a;\n  output y;\n  BUF b (.A(a), .Y(y[3]));\nendmodule\n";
+        match parse_str("top.v", src, |_, _| Some(PortDirection::Input)) {
+            Err(Error::ElaborationError(report)) => {
+                assert!(report.contains("index 3 out of range for net y"));
+                // located at the net slice, not the module
+                assert!(report.contains("top.v:4:20"));
+            }
+            r => panic!("expected an elaboration error, got {:?}", r),
+        }
+        let src =
+            "module top(a, y);\n  input a;\n  output y;\n  assign y = a, y = 1'b0;\nendmodule\n";
+        match parse_str("top.v", src, |_, _| Some(PortDirection::Input)) {
+            Err(Error::ElaborationError(report)) => {
+                assert!(report.contains("has multiple drivers"));
+                // located at the second assignment
+                assert!(report.contains("top.v:4:17"));
+            }
+            r => panic!("expected an elaboration error, got {:?}", r),
+        }
+    }
+
+    #[test]
+    fn yosys_netlist() -> Result<(), Error> {
+        let netlist_path = concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/",
+            "tests/Xoodyak/mkPerm_netlist.v"
+        );
+        let netlist = parse_file(netlist_path, |_, port| {
+            Some(match port {
+                "Y" | "Z" | "Q" => PortDirection::Output,
+                _ => PortDirection::Input,
+            })
+        })?;
+        let (name, top) = netlist.get_top().unwrap();
+        assert_eq!(name, "mkPerm");
+        // as in the JSON netlist written by Yosys along with it
+        assert_eq!(top.ports.len(), 9);
+        assert_eq!(top.cells.len(), 1930);
+        assert_eq!(top.ports["state_in_i"].bits.len(), 384);
+        Ok(())
+    }
+}
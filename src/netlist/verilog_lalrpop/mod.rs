@@ -1,2 +0,0 @@
-mod ast;
-mod reader;
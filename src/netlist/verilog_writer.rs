@@ -0,0 +1,228 @@
+use std::collections::HashMap;
+use std::io::{self, Write};
+
+use itertools::Itertools;
+
+use super::json_netlist::{AttributeVal, BitVal, ConstBit, Module, PortDirection, SignalId};
+
+const KEYWORDS: &[&str] = &[
+    "always",
+    "and",
+    "assign",
+    "begin",
+    "buf",
+    "case",
+    "default",
+    "else",
+    "end",
+    "endcase",
+    "endmodule",
+    "for",
+    "function",
+    "if",
+    "initial",
+    "inout",
+    "input",
+    "integer",
+    "module",
+    "nand",
+    "nor",
+    "not",
+    "or",
+    "output",
+    "parameter",
+    "reg",
+    "wire",
+    "xnor",
+    "xor",
+];
+
+/// Identifier as it should appear in Verilog source, escaped if needed
+pub fn verilog_ident(name: &str) -> String {
+    let mut chars = name.chars();
+    let is_simple = chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '$')
+        && !KEYWORDS.contains(&name);
+    if is_simple {
+        name.to_owned()
+    } else {
+        format!("\\{} ", name)
+    }
+}
+
+fn fmt_attribute_value(value: &AttributeVal) -> String {
+    match value {
+        AttributeVal::Number(n) => n.to_string(),
+        AttributeVal::String(s) => format!("{:?}", s),
+    }
+}
+
+fn fmt_attributes(attributes: &HashMap<String, AttributeVal>) -> Option<String> {
+    (!attributes.is_empty()).then(|| {
+        format!(
+            "(* {} *)",
+            attributes
+                .iter()
+                .sorted_by(|a, b| Ord::cmp(a.0, b.0))
+                .map(|(k, v)| format!("{} = {}", k, fmt_attribute_value(v)))
+                .join(", ")
+        )
+    })
+}
+
+fn fmt_range(width: usize) -> String {
+    if width > 1 {
+        format!("[{}:0] ", width - 1)
+    } else {
+        String::new()
+    }
+}
+
+fn fmt_bit_name(name: &str, width: usize, idx: usize) -> String {
+    if width > 1 {
+        format!("{}[{}]", verilog_ident(name), idx)
+    } else {
+        verilog_ident(name)
+    }
+}
+
+impl Module {
+    /// Write the module as structural Verilog.
+    /// Blackbox modules are written as empty `(* blackbox *)` stubs.
+    pub fn write_verilog<W: Write>(&self, name: &str, w: &mut W) -> io::Result<()> {
+        let ports = self
+            .ports
+            .iter()
+            .sorted_by(|a, b| {
+                Ord::cmp(
+                    &(a.1.direction != PortDirection::Input, a.0),
+                    &(b.1.direction != PortDirection::Input, b.0),
+                )
+            })
+            .collect_vec();
+
+        if self.is_blackbox() {
+            writeln!(w, "(* blackbox *)")?;
+        }
+        writeln!(
+            w,
+            "module {}({});",
+            verilog_ident(name),
+            ports.iter().map(|(n, _)| verilog_ident(n)).join(", ")
+        )?;
+
+        // name of each signal, ports take precedence over internal wires
+        let mut signal_names = HashMap::<SignalId, String>::new();
+        for (port_name, port) in ports.iter() {
+            let attributes = self
+                .netnames
+                .get(*port_name)
+                .and_then(|net| fmt_attributes(&net.attributes));
+            if let Some(attributes) = attributes {
+                writeln!(w, "  {}", attributes)?;
+            }
+            let direction = match port.direction {
+                PortDirection::Input => "input",
+                PortDirection::Output => "output",
+                PortDirection::InOut => "inout",
+            };
+            let width = port.bits.len();
+            writeln!(
+                w,
+                "  {} {}{};",
+                direction,
+                fmt_range(width),
+                verilog_ident(port_name)
+            )?;
+            for (idx, bit) in port.bits.iter().enumerate() {
+                if let BitVal::Signal(sig) = bit {
+                    // inputs are the drivers of their signals
+                    if port.direction == PortDirection::Input || !signal_names.contains_key(sig) {
+                        signal_names.insert(*sig, fmt_bit_name(port_name, width, idx));
+                    }
+                }
+            }
+        }
+        if self.is_blackbox() {
+            writeln!(w, "endmodule")?;
+            return Ok(());
+        }
+
+        let cells = self
+            .cells
+            .iter()
+            .sorted_by(|a, b| Ord::cmp(a.0, b.0))
+            .collect_vec();
+
+        let mut wires = Vec::new();
+        for (_, cell) in cells.iter() {
+            for bit in cell.connections.values().flatten() {
+                if let BitVal::Signal(sig) = bit {
+                    if !signal_names.contains_key(sig) {
+                        signal_names.insert(*sig, format!("_{}_", sig));
+                        wires.push(*sig);
+                    }
+                }
+            }
+        }
+        wires.sort();
+        for sig in wires {
+            writeln!(w, "  wire _{}_;", sig)?;
+        }
+
+        let fmt_bit = |bit: &BitVal| -> String {
+            match bit {
+                BitVal::Signal(sig) => signal_names[sig].clone(),
+                BitVal::Constant(ConstBit::_0) => "1'b0".to_owned(),
+                BitVal::Constant(ConstBit::_1) => "1'b1".to_owned(),
+                BitVal::Constant(ConstBit::X) => "1'bx".to_owned(),
+                BitVal::Constant(ConstBit::Z) => "1'bz".to_owned(),
+            }
+        };
+        let fmt_bits = |bits: &Vec<BitVal>| -> String {
+            if bits.len() == 1 {
+                fmt_bit(&bits[0])
+            } else {
+                format!("{{ {} }}", bits.iter().rev().map(fmt_bit).join(", "))
+            }
+        };
+
+        for (cell_name, cell) in cells {
+            if let Some(attributes) = fmt_attributes(&cell.attributes) {
+                writeln!(w, "  {}", attributes)?;
+            }
+            let connections = cell
+                .connections
+                .iter()
+                .sorted_by(|a, b| Ord::cmp(a.0, b.0))
+                .map(|(port, bits)| format!(".{}({})", verilog_ident(port), fmt_bits(bits)))
+                .join(", ");
+            writeln!(
+                w,
+                "  {} {} ({});",
+                verilog_ident(&cell.cell_type),
+                verilog_ident(cell_name),
+                connections
+            )?;
+        }
+
+        // output port bits which are not driven directly by a cell
+        for (port_name, port) in ports.iter() {
+            if port.direction != PortDirection::Output {
+                continue;
+            }
+            let width = port.bits.len();
+            for (idx, bit) in port.bits.iter().enumerate() {
+                let lhs = fmt_bit_name(port_name, width, idx);
+                let rhs = fmt_bit(bit);
+                if lhs != rhs {
+                    writeln!(w, "  assign {} = {};", lhs, rhs)?;
+                }
+            }
+        }
+        writeln!(w, "endmodule")?;
+        Ok(())
+    }
+}
@@ -1,6 +1,11 @@
+use ariadne::{Config, Label, Report, ReportKind, Source};
+use itertools::Itertools;
+use lalrpop_util::ParseError;
 use serde::de::{self, Deserializer, Unexpected};
+use serde::Serializer;
 use std::collections::hash_map;
 use std::collections::{HashMap, HashSet};
+use std::ops::Range;
 
 #[derive(Clone, Debug, Default)]
 pub struct MapToSet<K, V>(HashMap<K, HashSet<V>>);
@@ -60,3 +65,64 @@ where
         )),
     }
 }
+
+pub(crate) fn bool_to_int<S>(value: &bool, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    serializer.serialize_u8(u8::from(*value))
+}
+
+/// Render an error report for `span` of the source `src` named `name`
+pub(crate) fn source_report(
+    name: &str,
+    src: &str,
+    span: Range<usize>,
+    message: &str,
+    label: &str,
+) -> String {
+    let mut out = Vec::new();
+    Report::build(ReportKind::Error, name, span.start)
+        .with_config(Config::default().with_color(false))
+        .with_message(message)
+        .with_label(Label::new((name, span)).with_message(label))
+        .finish()
+        .write((name, Source::from(src)), &mut out)
+        .expect("writing to a Vec can't fail");
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+/// Render an error report for a LALRPOP parse error in `src`
+pub(crate) fn parse_error_report<T: std::fmt::Display, E: std::fmt::Display>(
+    name: &str,
+    src: &str,
+    error: ParseError<usize, T, E>,
+) -> String {
+    let expected_one_of = |expected: &[String]| {
+        if expected.is_empty() {
+            String::new()
+        } else {
+            format!(", expected one of {}", expected.iter().join(", "))
+        }
+    };
+    let (span, label) = match &error {
+        ParseError::InvalidToken { location } => {
+            (*location..*location + 1, "invalid token".to_owned())
+        }
+        ParseError::UnrecognizedEOF { location, expected } => (
+            *location..*location,
+            format!("unexpected end of file{}", expected_one_of(expected)),
+        ),
+        ParseError::UnrecognizedToken {
+            token: (l, t, r),
+            expected,
+        } => (
+            *l..*r,
+            format!("unexpected `{}`{}", t, expected_one_of(expected)),
+        ),
+        ParseError::ExtraToken { token: (l, t, r) } => (*l..*r, format!("extra token `{}`", t)),
+        ParseError::User { error } => (0..0, error.to_string()),
+    };
+    let span = span.start.min(src.len())..span.end.min(src.len());
+    source_report(name, src, span, "syntax error", &label)
+}